@@ -0,0 +1,93 @@
+//! Measures how many payloads a server can receive and echo back over loopback UDP.
+//!
+//! Run with `cargo bench --features batch-io` to compare `UdpSocket` against `BatchUdpSocket`, and against `UdpSocket` with
+//! encryption on worker threads.
+
+extern crate netcode;
+
+use netcode::*;
+
+use std::time::{Duration, Instant};
+use std::thread;
+
+const PROTOCOL_ID: u64 = 0xFFEE;
+const NUM_CLIENTS: usize = 64;
+const FRAMES: usize = 600;
+const PAYLOAD_SIZE: usize = 100;
+const FRAME_TIME: f64 = 1.0 / 60.0;
+
+fn connect_clients<T>(server: &mut Server<T>, private_key: &[u8; 32]) -> Vec<UdpClient> where T: BindTransport {
+    let addr = server.get_local_addr().unwrap();
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+    let mut clients = vec!();
+    for i in 0..NUM_CLIENTS {
+        let token = ConnectToken::generate([addr].iter().cloned(), private_key, 30, 0, PROTOCOL_ID, i as u64, None).unwrap();
+        clients.push(UdpClient::new(&token).unwrap());
+    }
+
+    while !clients.iter().all(|c| c.get_state() == State::Connected) {
+        server.update(FRAME_TIME).unwrap();
+        while let Some(_) = server.next_event(&mut payload).unwrap() {}
+        server.flush().unwrap();
+
+        for client in clients.iter_mut() {
+            client.update(FRAME_TIME).unwrap();
+            while let Some(_) = client.next_event(&mut payload).unwrap() {}
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    clients
+}
+
+fn bench<T>(name: &str, worker_threads: usize) where T: BindTransport {
+    let private_key = generate_key();
+    let mut server = Server::<T>::new("127.0.0.1:0", NUM_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+    server.set_worker_threads(worker_threads).unwrap();
+    let mut clients = connect_clients(&mut server, &private_key);
+
+    let data = [0xAA; PAYLOAD_SIZE];
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+    let mut server_received = 0;
+    let mut client_received = 0;
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        for client in clients.iter_mut() {
+            client.send(&data).unwrap();
+        }
+
+        server.update(FRAME_TIME).unwrap();
+        while let Some(event) = server.next_event(&mut payload).unwrap() {
+            if let ServerEvent::Packet(id, len, _) = event {
+                server_received += 1;
+                server.send(id, &payload[..len]).unwrap();
+            }
+        }
+        server.flush().unwrap();
+
+        for client in clients.iter_mut() {
+            client.update(FRAME_TIME).unwrap();
+            while let Some(event) = client.next_event(&mut payload).unwrap() {
+                if let ClientEvent::Packet(_, _) = event {
+                    client_received += 1;
+                }
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+    println!("{}: {} clients x {} frames in {:.3}s, server received {} ({:.0}/s), clients received {}",
+        name, NUM_CLIENTS, FRAMES, secs, server_received, server_received as f64 / secs, client_received);
+}
+
+fn main() {
+    bench::<::std::net::UdpSocket>("UdpSocket", 0);
+    bench::<::std::net::UdpSocket>("UdpSocket, 4 worker threads", 4);
+
+    #[cfg(all(target_os = "linux", feature = "batch-io"))]
+    bench::<BatchUdpSocket>("BatchUdpSocket", 0);
+}
@@ -1,11 +1,24 @@
+//! Builds the C library and its bindings for the interop tests, only with the `capi-tests` feature so downstream builds
+//! don't compile netcode.c or need clang.
+
+#[cfg(feature = "capi-tests")]
 extern crate gcc;
+#[cfg(feature = "capi-tests")]
 extern crate bindgen;
 
+#[cfg(feature = "capi-tests")]
 use std::env;
+#[cfg(feature = "capi-tests")]
 use std::path::PathBuf;
+#[cfg(feature = "capi-tests")]
 use std::fs::File;
+#[cfg(feature = "capi-tests")]
 use std::time::{Duration};
 
+#[cfg(not(feature = "capi-tests"))]
+pub fn main() {}
+
+#[cfg(feature = "capi-tests")]
 pub fn main() {
     gcc::Config::new()
         .file("../c/netcode.c")
@@ -0,0 +1,380 @@
+//! Stream based wrappers that drive `Server` and `Client` from a tokio event loop, enabled with the `async-tokio` feature.
+
+use common::*;
+use error::*;
+use server::{Server, ServerEvent};
+use client::{Client, ClientEvent, State};
+use socket::{Transport, BindTransport};
+
+use std::io;
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use futures_core::Stream;
+
+/// Netcode server over a `TokioUdpSocket`, it must be created from within a tokio runtime.
+pub type TokioServer = Server<TokioUdpSocket>;
+
+/// Netcode client over a `TokioUdpSocket`, it must be created from within a tokio runtime.
+pub type TokioClient = Client<TokioUdpSocket>;
+
+/// UDP transport registered with the tokio runtime so `AsyncServer` and `AsyncClient` are woken when packets arrive.
+pub struct TokioUdpSocket {
+    socket: tokio::net::UdpSocket,
+    /// tokio refuses to send until it has seen the socket become writable, which drops the first handshake packet.
+    /// UDP sends don't wait on the peer so we send on a plain handle to the same socket instead.
+    sender: net::UdpSocket
+}
+
+impl TokioUdpSocket {
+    /// Registers `socket` with the current tokio runtime, panics if called outside of one.
+    pub fn from_std(socket: net::UdpSocket) -> Result<TokioUdpSocket, io::Error> {
+        socket.set_nonblocking(true)?;
+        let sender = socket.try_clone()?;
+
+        Ok(TokioUdpSocket {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            sender: sender
+        })
+    }
+
+    fn poll_readable(&self, cx: &mut Context) -> Result<bool, UpdateError> {
+        match self.socket.poll_recv_ready(cx) {
+            Poll::Ready(Ok(())) => Ok(true),
+            Poll::Ready(Err(e)) => Err(RecvError::SocketError(e).into()),
+            Poll::Pending => Ok(false)
+        }
+    }
+}
+
+impl Transport for TokioUdpSocket {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.socket.try_recv_from(buf)
+    }
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Result<usize, io::Error> {
+        self.sender.send_to(buf, addr)
+    }
+}
+
+impl BindTransport for TokioUdpSocket {
+    fn bind(addr: &SocketAddr) -> Result<TokioUdpSocket, io::Error> {
+        Self::from_std(net::UdpSocket::bind(addr)?)
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Calls `update(..)` on a fixed interval with the real time that has passed.
+struct Ticker {
+    interval: Interval,
+    last_update: Instant
+}
+
+impl Ticker {
+    fn new(period: Duration) -> Ticker {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ticker {
+            interval: interval,
+            last_update: Instant::now()
+        }
+    }
+
+    /// Returns the seconds elapsed since the last tick if the timer fired, otherwise we'll be woken for the next one.
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Option<f64> {
+        match self.interval.poll_tick(cx) {
+            Poll::Ready(now) => {
+                let elapsed = now.duration_since(self.last_update);
+                self.last_update = now;
+
+                Some(duration_secs(elapsed))
+            },
+            Poll::Pending => None
+        }
+    }
+}
+
+/// Stream of `ServerEvent`s from a `TokioServer`.
+///
+/// Each item contains the event and its data: the payload for `ServerEvent::Packet` and the connect token user data for
+/// `ServerEvent::ClientConnect`, other events have no data. The stream wakes when a socket is readable and every `tick` to
+/// send keep-alives and time out clients. Loopback clients are only polled on ticks.
+/// # Example
+/// ```ignore
+/// let server = TokioServer::new("127.0.0.1:40000", MAX_CLIENTS, PROTOCOL_ID, &private_key)?;
+/// let mut server = AsyncServer::new(server, Duration::from_millis(100));
+///
+/// while let Some(event) = server.next().await {
+///     match event? {
+///         (ServerEvent::Packet(id, _, _), payload) => { server.get_mut().send(id, &payload)?; },
+///         _ => ()
+///     }
+/// }
+/// ```
+pub struct AsyncServer {
+    server: TokioServer,
+    ticker: Ticker,
+    payload: [u8; NETCODE_MAX_PAYLOAD_SIZE]
+}
+
+impl AsyncServer {
+    /// Wraps `server`, calling `update(..)` every `tick` to drive keep-alives and timeouts.
+    pub fn new(server: TokioServer, tick: Duration) -> AsyncServer {
+        AsyncServer {
+            server: server,
+            ticker: Ticker::new(tick),
+            payload: [0; NETCODE_MAX_PAYLOAD_SIZE]
+        }
+    }
+
+    /// Gets the wrapped server.
+    pub fn get_ref(&self) -> &TokioServer {
+        &self.server
+    }
+
+    /// Gets the wrapped server, used to send packets and disconnect clients.
+    pub fn get_mut(&mut self) -> &mut TokioServer {
+        &mut self.server
+    }
+
+    /// Unwraps the server, it will no longer be updated.
+    pub fn into_inner(self) -> TokioServer {
+        self.server
+    }
+}
+
+impl Stream for AsyncServer {
+    type Item = Result<(ServerEvent, Vec<u8>), UpdateError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let ticked = match this.ticker.poll_elapsed(cx) {
+                Some(elapsed) => {
+                    if let Err(e) = this.server.update(elapsed) {
+                        return Poll::Ready(Some(Err(RecvError::SocketError(e).into())))
+                    }
+
+                    true
+                },
+                None => false
+            };
+
+            match this.server.next_event(&mut this.payload) {
+                Ok(Some(event)) => {
+                    let data = match event {
+                        ServerEvent::Packet(_, len, _) => this.payload[..len].to_vec(),
+                        ServerEvent::ClientConnect(_) => this.payload[..NETCODE_USER_DATA_BYTES].to_vec(),
+                        _ => vec!()
+                    };
+
+                    return Poll::Ready(Some(Ok((event, data))))
+                },
+                Ok(None) => (),
+                Err(e) => return Poll::Ready(Some(Err(e)))
+            }
+
+            //Nothing pending, keep going while there's more to read otherwise wait on the sockets and timer
+            let mut readable = false;
+            for socket in this.server.transports() {
+                match socket.poll_readable(cx) {
+                    Ok(ready) => readable = readable || ready,
+                    Err(e) => return Poll::Ready(Some(Err(e)))
+                }
+            }
+
+            if !readable && !ticked {
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+/// Stream of `ClientEvent`s from a `TokioClient`.
+///
+/// Each item contains the event and the payload for `ClientEvent::Packet`, other events have no data. The stream wakes when
+/// the socket is readable and every `tick` to resend handshake packets and keep-alives. It ends after the client moves to a
+/// state where it is no longer connecting or connected.
+pub struct AsyncClient {
+    client: TokioClient,
+    ticker: Ticker,
+    payload: [u8; NETCODE_MAX_PAYLOAD_SIZE],
+    finished: bool
+}
+
+impl AsyncClient {
+    /// Wraps `client`, calling `update(..)` every `tick` to drive keep-alives and timeouts.
+    pub fn new(client: TokioClient, tick: Duration) -> AsyncClient {
+        AsyncClient {
+            client: client,
+            ticker: Ticker::new(tick),
+            payload: [0; NETCODE_MAX_PAYLOAD_SIZE],
+            finished: false
+        }
+    }
+
+    /// Gets the wrapped client.
+    pub fn get_ref(&self) -> &TokioClient {
+        &self.client
+    }
+
+    /// Gets the wrapped client, used to send packets and disconnect.
+    pub fn get_mut(&mut self) -> &mut TokioClient {
+        &mut self.client
+    }
+
+    /// Unwraps the client, it will no longer be updated.
+    pub fn into_inner(self) -> TokioClient {
+        self.client
+    }
+}
+
+impl Stream for AsyncClient {
+    type Item = Result<(ClientEvent, Vec<u8>), UpdateError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.finished {
+            return Poll::Ready(None)
+        }
+
+        loop {
+            let ticked = match this.ticker.poll_elapsed(cx) {
+                Some(elapsed) => {
+                    if let Err(e) = this.client.update(elapsed) {
+                        return Poll::Ready(Some(Err(e)))
+                    }
+
+                    true
+                },
+                None => false
+            };
+
+            match this.client.next_event(&mut this.payload) {
+                Ok(Some(event)) => {
+                    let data = match event {
+                        ClientEvent::Packet(len, _) => this.payload[..len].to_vec(),
+                        ClientEvent::NewState(ref state) => {
+                            this.finished = match *state {
+                                State::SendingConnectionRequest | State::SendingConnectionResponse | State::Connected => false,
+                                _ => true
+                            };
+
+                            vec!()
+                        },
+                        _ => vec!()
+                    };
+
+                    return Poll::Ready(Some(Ok((event, data))))
+                },
+                Ok(None) => (),
+                Err(e) => return Poll::Ready(Some(Err(e)))
+            }
+
+            let readable = match this.client.transport() {
+                Some(socket) => match socket.poll_readable(cx) {
+                    Ok(ready) => ready,
+                    Err(e) => return Poll::Ready(Some(Err(e)))
+                },
+                None => false
+            };
+
+            if !readable && !ticked {
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn test_async_client_server() {
+    use token::ConnectToken;
+    use crypto;
+    use std::future;
+
+    const PROTOCOL_ID: u64 = 0xFFCC;
+    const CLIENT_ID: u64 = 0xFFEEDD;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let _guard = runtime.enter();
+
+    let private_key = crypto::generate_key();
+    let server = TokioServer::new("127.0.0.1:0", 4, PROTOCOL_ID, &private_key).unwrap();
+    let addr = server.get_local_addr().unwrap();
+    let mut server = AsyncServer::new(server, Duration::from_millis(10));
+
+    let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+    let mut client = AsyncClient::new(TokioClient::new(&token).unwrap(), Duration::from_millis(10));
+
+    let mut server_payload = false;
+    let mut client_payload = false;
+    let mut server_disconnect = false;
+    let mut client_finished = false;
+
+    let run = future::poll_fn(|cx| {
+        while let Poll::Ready(Some(event)) = Pin::new(&mut server).poll_next(cx) {
+            match event.unwrap() {
+                (ServerEvent::ClientConnect(id), _) => {
+                    assert_eq!(id, CLIENT_ID);
+                    server.get_mut().send(id, &[1, 2, 3]).unwrap();
+                },
+                (ServerEvent::Packet(id, 3, _), payload) => {
+                    assert_eq!(id, CLIENT_ID);
+                    assert_eq!(payload, vec!(4, 5, 6));
+                    server_payload = true;
+                },
+                (ServerEvent::ClientDisconnect(id), _) => {
+                    assert_eq!(id, CLIENT_ID);
+                    server_disconnect = true;
+                },
+                _ => ()
+            }
+        }
+
+        loop {
+            match Pin::new(&mut client).poll_next(cx) {
+                Poll::Ready(Some(event)) => match event.unwrap() {
+                    (ClientEvent::NewState(State::Connected), _) => {
+                        client.get_mut().send(&[4, 5, 6]).unwrap();
+                    },
+                    (ClientEvent::Packet(3, _), payload) => {
+                        assert_eq!(payload, vec!(1, 2, 3));
+                        client_payload = true;
+                    },
+                    (ClientEvent::NewState(state), _) => assert!(state == State::SendingConnectionRequest || state == State::SendingConnectionResponse || state == State::Disconnected, "{:?}", state),
+                    _ => ()
+                },
+                Poll::Ready(None) => {
+                    client_finished = true;
+                    break
+                },
+                Poll::Pending => break
+            }
+        }
+
+        if server_payload && client_payload && client.get_ref().get_state() == State::Connected {
+            client.get_mut().disconnect().unwrap();
+        }
+
+        if server_disconnect && client_finished {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+
+    runtime.block_on(time::timeout(Duration::from_secs(5), run)).unwrap();
+}
@@ -0,0 +1,388 @@
+//! Linux UDP transport that moves datagrams in batches with `recvmmsg`/`sendmmsg`, enabled with the `batch-io` feature.
+
+use common::*;
+use server::Server;
+use socket::{Transport, BindTransport};
+
+use std::net::{UdpSocket, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io;
+use std::mem;
+use std::ptr;
+use std::cmp;
+use std::time::Duration;
+
+use libc;
+
+/// Number of datagrams moved per syscall.
+const BATCH_SIZE: usize = 32;
+
+/// Server that receives and sends in batches, see [BatchUdpSocket](struct.BatchUdpSocket.html).
+pub type BatchUdpServer = Server<BatchUdpSocket>;
+
+/// UDP transport that receives up to 32 datagrams per syscall and holds sent datagrams until `flush()`.
+///
+/// `recv_from(..)` hands out datagrams from the last batch and only goes back to the socket once it's empty. `send_to(..)` queues
+/// the datagram and sends the whole queue in one syscall when it's full or `Server::flush()`/`Client::flush()` is called, so call
+/// flush once per frame after sending payloads and draining `next_event(..)` since keep-alives and handshake replies are queued too.
+pub struct BatchUdpSocket {
+    socket: UdpSocket,
+    blocking: bool,
+    recv_data: Vec<u8>,
+    /// Length and sender of each datagram in the current batch.
+    recv_packets: Vec<(usize, SocketAddr)>,
+    recv_next: usize,
+    send_data: Vec<u8>,
+    send_packets: Vec<(usize, SocketAddr)>
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Result<SocketAddr, io::Error> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported address family"))
+    }
+}
+
+/// Writes `addr` into zeroed `storage`, returns the length of the address.
+fn to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            let out = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            out.sin_family = libc::AF_INET as libc::sa_family_t;
+            out.sin_port = addr.port().to_be();
+            out.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        },
+        SocketAddr::V6(ref addr) => {
+            let out = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            out.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            out.sin6_port = addr.port().to_be();
+            out.sin6_flowinfo = addr.flowinfo();
+            out.sin6_addr.s6_addr = addr.ip().octets();
+            out.sin6_scope_id = addr.scope_id();
+
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+impl BatchUdpSocket {
+    /// Wraps an already bound `socket`, it's switched to non-blocking.
+    pub fn from_std(socket: UdpSocket) -> Result<BatchUdpSocket, io::Error> {
+        socket.set_nonblocking(true)?;
+
+        Ok(BatchUdpSocket {
+            socket: socket,
+            blocking: false,
+            recv_data: vec!(0; BATCH_SIZE * NETCODE_MAX_PACKET_SIZE),
+            recv_packets: Vec::with_capacity(BATCH_SIZE),
+            recv_next: 0,
+            send_data: vec!(0; BATCH_SIZE * NETCODE_MAX_PACKET_SIZE),
+            send_packets: Vec::with_capacity(BATCH_SIZE)
+        })
+    }
+
+    fn recv_batch(&mut self) -> Result<(), io::Error> {
+        self.recv_packets.clear();
+        self.recv_next = 0;
+
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for i in 0..BATCH_SIZE {
+            iovecs[i].iov_base = self.recv_data[i * NETCODE_MAX_PACKET_SIZE..].as_mut_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = NETCODE_MAX_PACKET_SIZE;
+
+            msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        //Blocking sockets wait for the first datagram then take whatever else is ready
+        let flags = if self.blocking { libc::MSG_WAITFORONE } else { libc::MSG_DONTWAIT };
+        let count = unsafe { libc::recvmmsg(self.socket.as_raw_fd(), msgs.as_mut_ptr(), BATCH_SIZE as libc::c_uint, flags, ptr::null_mut()) };
+        if count < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        for i in 0..count as usize {
+            self.recv_packets.push((msgs[i].msg_len as usize, from_sockaddr(&addrs[i])?));
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for BatchUdpSocket {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        if self.recv_next >= self.recv_packets.len() {
+            self.recv_batch()?;
+
+            if self.recv_packets.len() == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "No packets available"))
+            }
+        }
+
+        let idx = self.recv_next;
+        self.recv_next += 1;
+
+        //Match UdpSocket and truncate anything that doesn't fit
+        let (len, addr) = self.recv_packets[idx];
+        let len = cmp::min(len, buf.len());
+        let offset = idx * NETCODE_MAX_PACKET_SIZE;
+        buf[..len].copy_from_slice(&self.recv_data[offset..offset + len]);
+
+        Ok((len, addr))
+    }
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Result<usize, io::Error> {
+        if buf.len() > NETCODE_MAX_PACKET_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Datagram larger than NETCODE_MAX_PACKET_SIZE"))
+        }
+
+        //The datagram is still queued if flushing fails, the error belongs to whoever the failed datagram was for
+        if self.send_packets.len() >= BATCH_SIZE {
+            if let Err(e) = self.flush() {
+                info!("Failed to send part of a full batch: {:?}", e);
+            }
+        }
+
+        let offset = self.send_packets.len() * NETCODE_MAX_PACKET_SIZE;
+        self.send_data[offset..offset + buf.len()].copy_from_slice(buf);
+        self.send_packets.push((buf.len(), addr.clone()));
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, &(len, ref addr)) in self.send_packets.iter().enumerate() {
+            iovecs[i].iov_base = self.send_data[i * NETCODE_MAX_PACKET_SIZE..].as_mut_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = len;
+
+            msgs[i].msg_hdr.msg_namelen = to_sockaddr(addr, &mut addrs[i]);
+            msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        //Kernel stops at the first datagram it can't send, skip just that one and keep going so other destinations still get theirs
+        let mut sent = 0;
+        let mut first_error = None;
+        while sent < self.send_packets.len() {
+            let count = unsafe { libc::sendmmsg(self.socket.as_raw_fd(), msgs[sent..].as_mut_ptr(), (self.send_packets.len() - sent) as libc::c_uint, 0) };
+            if count < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue
+                }
+
+                trace!("Dropping datagram to {:?}: {:?}", self.send_packets[sent].1, err);
+                first_error = first_error.or(Some(err));
+                sent += 1;
+                continue
+            }
+
+            sent += count as usize;
+        }
+
+        self.send_packets.clear();
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    fn set_recv_timeout(&mut self, duration: Option<Duration>) -> Result<(), io::Error> {
+        Transport::set_recv_timeout(&mut self.socket, duration)?;
+        self.blocking = duration.is_some();
+
+        Ok(())
+    }
+}
+
+impl BindTransport for BatchUdpSocket {
+    fn bind(addr: &SocketAddr) -> Result<BatchUdpSocket, io::Error> {
+        Self::from_std(UdpSocket::bind(addr)?)
+    }
+}
+
+impl AsRawFd for BatchUdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for BatchUdpSocket {
+    fn drop(&mut self) {
+        //Make sure queued disconnect packets go out
+        if let Err(e) = self.flush() {
+            info!("Failed to flush batch socket on drop: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+fn recv_all(socket: &mut BatchUdpSocket) -> Vec<(Vec<u8>, SocketAddr)> {
+    let mut received = vec!();
+    let mut buf = [0; NETCODE_MAX_PACKET_SIZE];
+
+    //Loopback delivery is quick but not instant
+    for _ in 0..100 {
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => received.push((buf[..len].to_vec(), addr)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => ::std::thread::sleep(Duration::from_millis(1)),
+            Err(e) => panic!("{:?}", e)
+        }
+    }
+
+    received
+}
+
+#[test]
+fn test_batch_send_recv() {
+    for bind_addr in ["127.0.0.1:0", "[::1]:0"].iter() {
+        use std::str::FromStr;
+        let bind_addr = SocketAddr::from_str(bind_addr).unwrap();
+
+        let mut a = BatchUdpSocket::bind(&bind_addr).unwrap();
+        let mut b = BatchUdpSocket::bind(&bind_addr).unwrap();
+        let a_addr = a.local_addr().unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        //Nothing goes out until flushed
+        for i in 0..3 {
+            a.send_to(&[i; 10], &b_addr).unwrap();
+        }
+        assert_eq!(recv_all(&mut b), vec!());
+
+        a.flush().unwrap();
+        let received = recv_all(&mut b);
+        assert_eq!(received.len(), 3);
+        for (i, &(ref data, addr)) in received.iter().enumerate() {
+            assert_eq!(data, &vec!(i as u8; 10));
+            assert_eq!(addr, a_addr);
+        }
+
+        //Full batches are sent without waiting for a flush
+        for i in 0..BATCH_SIZE * 2 + 1 {
+            b.send_to(&[i as u8], &a_addr).unwrap();
+        }
+        assert_eq!(recv_all(&mut a).len(), BATCH_SIZE * 2);
+
+        b.flush().unwrap();
+        assert_eq!(recv_all(&mut a), vec!((vec!(BATCH_SIZE as u8 * 2), b_addr)));
+
+        assert!(a.send_to(&[0; NETCODE_MAX_PACKET_SIZE + 1], &b_addr).is_err());
+    }
+}
+
+#[test]
+fn test_batch_send_error() {
+    use std::str::FromStr;
+
+    let mut a = BatchUdpSocket::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+    let mut b = BatchUdpSocket::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+    let b_addr = b.local_addr().unwrap();
+    let bad_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+
+    //A datagram the kernel refuses only costs that datagram
+    a.send_to(&[1], &b_addr).unwrap();
+    a.send_to(&[2], &bad_addr).unwrap();
+    a.send_to(&[3], &b_addr).unwrap();
+    assert!(a.flush().is_err());
+    assert_eq!(recv_all(&mut b).iter().map(|&(ref data, _)| data[0]).collect::<Vec<_>>(), vec!(1, 3));
+
+    //Implicit flush of a full batch doesn't fail or drop the datagram being queued
+    a.send_to(&[0], &bad_addr).unwrap();
+    for i in 1..BATCH_SIZE + 1 {
+        a.send_to(&[i as u8], &b_addr).unwrap();
+    }
+    a.flush().unwrap();
+    assert_eq!(recv_all(&mut b).len(), BATCH_SIZE);
+}
+
+#[test]
+fn test_batch_client_server() {
+    use client::{Client, ClientEvent, State};
+    use server::ServerEvent;
+    use token::ConnectToken;
+    use crypto;
+
+    const PROTOCOL_ID: u64 = 0xFFCC;
+    const NUM_CLIENTS: usize = 8;
+
+    let private_key = crypto::generate_key();
+    let mut server = BatchUdpServer::new("127.0.0.1:0", NUM_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+    let addr = server.get_local_addr().unwrap();
+
+    let mut clients = vec!();
+    for i in 0..NUM_CLIENTS {
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, i as u64, None).unwrap();
+        let mut client = Client::<BatchUdpSocket>::new(&token).unwrap();
+        client.flush().unwrap();
+        clients.push(client);
+    }
+
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+    let mut received = vec!(0; NUM_CLIENTS);
+    for _ in 0..1000 {
+        server.update(0.01).unwrap();
+        while let Some(event) = server.next_event(&mut payload).unwrap() {
+            if let ServerEvent::Packet(id, len, _) = event {
+                assert_eq!(&payload[..len], &[id as u8; 4]);
+                server.send(id, &payload[..len]).unwrap();
+            }
+        }
+        server.flush().unwrap();
+
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.update(0.01).unwrap();
+            while let Some(event) = client.next_event(&mut payload).unwrap() {
+                match event {
+                    ClientEvent::Packet(len, _) => {
+                        assert_eq!(&payload[..len], &[i as u8; 4]);
+                        received[i] += 1;
+                    },
+                    ClientEvent::NewState(state) => assert!(state == State::SendingConnectionResponse || state == State::Connected, "{:?}", state),
+                    _ => ()
+                }
+            }
+
+            if client.get_state() == State::Connected {
+                client.send(&[i as u8; 4]).unwrap();
+            }
+            client.flush().unwrap();
+        }
+
+        if received.iter().all(|r| *r >= 10) {
+            break
+        }
+
+        ::std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert!(received.iter().all(|r| *r >= 10), "{:?}", received);
+}
@@ -2,57 +2,72 @@ use common::*;
 use error::*;
 use replay::ReplayProtection;
 use packet::{self, Packet, KeepAlivePacket};
-use socket::SocketProvider;
+use socket::Transport;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub const TIMEOUT_SECONDS: u32 = 5;
 pub const KEEPALIVE_RETRY: f64 = 1.0 / 10.0;
 
 #[derive(Clone, Debug)]
 pub struct KeepAliveState {
     pub last_sent: f64,
-    pub last_response: f64
+    pub last_response: f64,
+    pub timeout: f64
 }
 
 impl KeepAliveState {
-    pub fn new() -> KeepAliveState {
+    pub fn new(time: f64, timeout_sec: u32) -> KeepAliveState {
         KeepAliveState {
-            last_sent: 0.0,
-            last_response: 0.0
+            last_sent: time,
+            last_response: time,
+            timeout: timeout_sec as f64
         }
     }
 
     pub fn update_sent(&self, time: f64) -> KeepAliveState {
         KeepAliveState {
             last_sent: time,
-            last_response: self.last_response
+            last_response: self.last_response,
+            timeout: self.timeout
         }
     }
 
     pub fn update_response(&self, response: f64) -> KeepAliveState {
         KeepAliveState {
             last_sent: self.last_sent,
-            last_response: response
+            last_response: response,
+            timeout: self.timeout
         }
     }
 
     pub fn has_expired(&self, time: f64) -> bool {
-        self.last_response + (TIMEOUT_SECONDS as f64) < time
+        self.expire_deadline() <= time
     }
 
     pub fn should_send_keepalive(&self, time: f64) -> bool {
-        self.last_sent + KEEPALIVE_RETRY < time
+        self.last_sent + KEEPALIVE_RETRY <= time
+    }
+
+    /// Time at which the connection expires if we don't hear from the other side.
+    pub fn expire_deadline(&self) -> f64 {
+        self.last_response + self.timeout
+    }
+
+    /// Time at which a keep alive is due or the connection expires, whichever comes first.
+    pub fn next_deadline(&self) -> f64 {
+        (self.last_sent + KEEPALIVE_RETRY).min(self.expire_deadline())
     }
 }
 
-#[derive(Clone)]
 pub struct Channel {
     keep_alive: KeepAliveState,
     send_key: [u8; NETCODE_KEY_BYTES],
     recv_key: [u8; NETCODE_KEY_BYTES],
     replay_protection: ReplayProtection,
-    next_sequence: u64,
+    /// Shared with `ServerSender`s so packets sent from other threads never reuse a sequence number.
+    next_sequence: Arc<AtomicU64>,
     addr: SocketAddr,
     protocol_id: u64,
     client_idx: usize,
@@ -71,13 +86,15 @@ impl Channel {
                addr: &SocketAddr,
                protocol_id: u64,
                client_idx: usize,
-               max_clients: usize) -> Channel {
+               max_clients: usize,
+               timeout_sec: u32,
+               time: f64) -> Channel {
         Channel {
-            keep_alive: KeepAliveState::new(),
+            keep_alive: KeepAliveState::new(time, timeout_sec),
             send_key: send_key.clone(),
             recv_key: recv_key.clone(),
             replay_protection: ReplayProtection::new(),
-            next_sequence: 0,
+            next_sequence: Arc::new(AtomicU64::new(0)),
             addr: addr.clone(),
             protocol_id: protocol_id,
             client_idx: client_idx,
@@ -85,31 +102,51 @@ impl Channel {
         }
     }
 
-    pub fn send<I,S>(&mut self, elapsed: f64, packet: &Packet, payload: Option<&[u8]>, socket: &mut I) -> Result<usize, SendError> where I: SocketProvider<I,S> {
+    pub fn send<T>(&mut self, elapsed: f64, packet: &Packet, payload: Option<&[u8]>, socket: &mut T) -> Result<usize, SendError> where T: Transport {
         let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
-        let len = packet::encode(&mut scratch, self.protocol_id, packet, Some((self.next_sequence, &self.send_key)), payload)?;
+        self.send_with_scratch(elapsed, &mut scratch, packet, payload, socket)
+    }
+
+    /// Same as `send(..)` but encodes into `scratch`, lets a caller sending to many channels reuse one buffer.
+    pub fn send_with_scratch<T>(&mut self, elapsed: f64, scratch: &mut [u8; NETCODE_MAX_PACKET_SIZE], packet: &Packet, payload: Option<&[u8]>, socket: &mut T)
+            -> Result<usize, SendError> where T: Transport {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let len = packet::encode(&mut scratch[..], self.protocol_id, packet, Some((sequence, &self.send_key)), payload)?;
 
         socket.send_to(&scratch[..len], &self.addr)?;
 
-        self.next_sequence += 1;
-        self.keep_alive.update_sent(elapsed);
+        self.keep_alive = self.keep_alive.update_sent(elapsed);
 
         Ok(len)
     }
 
-    pub fn recv(&mut self, elapsed: f64, packet: &[u8], out_payload: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Packet, RecvError> {
+    pub fn recv(&mut self, elapsed: f64, packet: &[u8], out_payload: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<(u64, Packet), RecvError> {
         let (seq, packet) = packet::decode(packet, self.protocol_id, Some(&self.recv_key), out_payload)?;
+        self.accept(elapsed, seq)?;
 
-        if self.replay_protection.packet_already_received(seq) {
+        Ok((seq, packet))
+    }
+
+    /// Checks replay protection for a packet that was decrypted elsewhere, counts it as a response if it's new.
+    pub fn accept(&mut self, elapsed: f64, sequence: u64) -> Result<(), RecvError> {
+        if self.replay_protection.packet_already_received(sequence) {
             return Err(RecvError::DuplicateSequence)
         }
 
-        self.keep_alive.update_response(elapsed);
+        self.keep_alive = self.keep_alive.update_response(elapsed);
 
-        Ok(packet)
+        Ok(())
     }
 
-    pub fn send_keep_alive<I,S>(&mut self, elapsed: f64, socket: &mut I) -> Result<usize, SendError> where I: SocketProvider<I,S> {
+    /// Takes the next sequence number for a packet that will be encrypted elsewhere.
+    pub fn reserve_sequence(&mut self, elapsed: f64) -> u64 {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        self.keep_alive = self.keep_alive.update_sent(elapsed);
+
+        sequence
+    }
+
+    pub fn send_keep_alive<T>(&mut self, elapsed: f64, socket: &mut T) -> Result<usize, SendError> where T: Transport {
         let keep_alive = KeepAlivePacket {
             client_idx: self.client_idx as i32,
             max_clients: self.max_clients as i32
@@ -118,7 +155,12 @@ impl Channel {
         self.send(elapsed, &Packet::KeepAlive(keep_alive), None, socket)
     }
 
-    pub fn update<I,S>(&mut self, elapsed: f64, socket: &mut I, send_keep_alive: bool) -> Result<UpdateResult, SendError> where I: SocketProvider<I,S> {
+    pub fn update<T>(&mut self, elapsed: f64, socket: &mut T, send_keep_alive: bool) -> Result<UpdateResult, SendError> where T: Transport {
+        //Check expiry first so a steady stream of keep alives can't mask a dead connection
+        if self.keep_alive.has_expired(elapsed) {
+            return Ok(UpdateResult::Expired)
+        }
+
         if self.keep_alive.should_send_keepalive(elapsed) {
             if send_keep_alive {
                 self.send_keep_alive(elapsed, socket)?;
@@ -127,14 +169,51 @@ impl Channel {
             return Ok(UpdateResult::SentKeepAlive)
         }
 
-        if self.keep_alive.has_expired(elapsed) {
-            return Ok(UpdateResult::Expired)
+        Ok(UpdateResult::Noop)
+    }
+
+    /// Counts a packet someone else sent on our behalf at `elapsed`, such as a `ServerSender`, towards keep alives.
+    pub fn mark_sent(&mut self, elapsed: f64) {
+        if elapsed > self.keep_alive.last_sent {
+            self.keep_alive = self.keep_alive.update_sent(elapsed);
         }
+    }
 
-        Ok(UpdateResult::Noop)
+    pub fn get_next_sequence(&self) -> u64 {
+        self.next_sequence.load(Ordering::Relaxed)
+    }
+
+    /// Sequence counter to hand to another sender, both sides draw from it.
+    pub fn shared_sequence(&self) -> Arc<AtomicU64> {
+        self.next_sequence.clone()
+    }
+
+    pub fn get_send_key(&self) -> &[u8; NETCODE_KEY_BYTES] {
+        &self.send_key
+    }
+
+    pub fn get_recv_key(&self) -> &[u8; NETCODE_KEY_BYTES] {
+        &self.recv_key
     }
 
     pub fn get_addr(&self) -> &SocketAddr {
         &self.addr
     }
+
+    pub fn has_expired(&self, elapsed: f64) -> bool {
+        self.keep_alive.has_expired(elapsed)
+    }
+
+    pub fn next_deadline(&self) -> f64 {
+        self.keep_alive.next_deadline()
+    }
+
+    pub fn expire_deadline(&self) -> f64 {
+        self.keep_alive.expire_deadline()
+    }
+
+    pub fn set_client_idx(&mut self, client_idx: usize, max_clients: usize) {
+        self.client_idx = client_idx;
+        self.max_clients = max_clients;
+    }
 }
\ No newline at end of file
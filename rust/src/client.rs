@@ -2,16 +2,25 @@ use common::*;
 use error::*;
 use channel::{self, Channel};
 use packet;
-use socket::SocketProvider;
-use token::ConnectToken;
+use socket::{Transport, BindTransport};
+use token::{self, ConnectToken, HostList};
+use loopback::LoopbackEndpoint;
 
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{SocketAddr, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::io;
+use std::collections::VecDeque;
 #[cfg(test)]
 use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(all(unix, feature = "mio"))]
+use mio;
+#[cfg(all(unix, feature = "mio"))]
+use mio::unix::SourceFd;
 
 /// States represented by the client
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum State {
     /// ConnectToken is expired.
     ConnectTokenExpired,
@@ -38,6 +47,7 @@ pub enum State {
 enum InternalState {
     Connecting(usize, ConnectSequence),
     Connected,
+    Loopback(LoopbackEndpoint),
     Disconnected
 }
 
@@ -62,44 +72,109 @@ pub enum ClientEvent {
     NewState(State),
     /// Channel is idle and client has sent keep alive packet.
     SentKeepAlive,
-    /// Client received packet of `usize` length, packet data is stored in `payload`.
-    Packet(usize)
+    /// Client received packet of `usize` length with sequence `u64`, packet data is stored in `payload`.
+    Packet(usize, u64)
 }
 
 /// Netcode client object.
-pub struct Client<I,S> where I: SocketProvider<I,S> {
+pub struct Client<T> where T: Transport {
     state: InternalState,
-    data: ClientData<I,S>
+    data: ClientData<T>
 }
 
-struct ClientData<I,S> where I: SocketProvider<I,S> {
+struct ClientData<T> where T: Transport {
     time: f64,
     ext_state: State,
     channel: Channel,
-    socket: I,
-    #[allow(dead_code)]
-    socket_state: S,
-    token: ConnectToken
+    /// `None` for loopback clients.
+    socket: Option<T>,
+    /// Set when we picked the bind address, used to bind the wildcard address of each host's family.
+    rebind: Option<fn(&SocketAddr) -> Result<T, io::Error>>,
+    token: ConnectToken,
+    /// Seconds the token had left when the client was created, compared against `time`.
+    token_lifetime: f64,
+    /// Both `None` until the server's first keep-alive tells us our slot.
+    client_idx: Option<usize>,
+    max_clients: Option<usize>,
+    pending_events: VecDeque<ClientEvent>
 }
 
 /// UDP based netcode client.
-pub type UdpClient = Client<UdpSocket, ()>;
+pub type UdpClient = Client<UdpSocket>;
+
+fn any_addr(ipv6: bool) -> SocketAddr {
+    if ipv6 {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0)
+    } else {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+    }
+}
 
-impl<I,S> ClientData<I,S> where I: SocketProvider<I,S> {
-    fn disconnect(&mut self, new_state: &mut Option<InternalState>) -> Result<Option<ClientEvent>, UpdateError> {
-        self.ext_state = State::Disconnected;
+impl<T> ClientData<T> where T: Transport {
+    fn disconnect(&mut self, state: State, new_state: &mut Option<InternalState>) -> Result<Option<ClientEvent>, UpdateError> {
+        self.ext_state = state;
         *new_state = Some(InternalState::Disconnected);
 
         Ok(Some(ClientEvent::NewState(self.ext_state.clone())))
     }
 
+    /// Slot is only meaningful while connected to the server that assigned it.
+    fn clear_client_index(&mut self) {
+        self.client_idx = None;
+        self.max_clients = None;
+    }
+
+    fn token_expired(&self) -> bool {
+        self.time >= self.token_lifetime
+    }
+
+    fn connect_next_host(&mut self, idx: usize, failed_state: State, new_state: &mut Option<InternalState>) -> Result<Option<ClientEvent>, UpdateError> {
+        let next_idx = idx + 1;
+
+        if self.token_expired() {
+            info!("Connect token expired, disconnecting");
+            self.disconnect(State::ConnectTokenExpired, new_state)
+        } else if next_idx >= self.token.hosts.get().len() {
+            info!("Failed to connect to last host, disconnecting");
+            self.disconnect(failed_state, new_state)
+        } else {
+            trace!("Failed to connect to host {:?}, moving to next host", self.channel.get_addr());
+
+            *new_state = Some(InternalState::Connecting(next_idx, ConnectSequence::SendingToken));
+            self.begin_host_connect(next_idx)
+                .map_err(|e| e.into())
+        }
+    }
+
     fn update_channel(&mut self, send_keep_alive: bool) -> Result<channel::UpdateResult, UpdateError> {
-        self.channel.update(self.time, &mut self.socket, send_keep_alive).map_err(|e| e.into())
+        match self.socket {
+            Some(ref mut socket) => self.channel.update(self.time, socket, send_keep_alive).map_err(|e| e.into()),
+            None => Ok(channel::UpdateResult::Noop)
+        }
+    }
+
+    fn send_packet(&mut self, packet: &packet::Packet, payload: Option<&[u8]>) -> Result<usize, SendError> {
+        match self.socket {
+            Some(ref mut socket) => self.channel.send(self.time, packet, payload, socket),
+            None => Err(SendError::Disconnected)
+        }
     }
 
-    fn connect_channel(&mut self, idx: usize) {
+    fn connect_channel(&mut self, idx: usize) -> Result<(), SendError> {
         match self.token.hosts.get().skip(idx).next() {
             Some(ref addr) => {
+                //Tokens can mix address families so we may need a new socket to reach this host
+                let rebind = match (self.rebind, self.socket.as_ref()) {
+                    (Some(rebind), Some(socket)) if socket.local_addr()?.is_ipv4() != addr.is_ipv4() => Some(rebind),
+                    _ => None
+                };
+
+                if let Some(rebind) = rebind {
+                    let socket = rebind(&any_addr(addr.is_ipv6()))?;
+                    trace!("Rebound client socket to {:?}", socket.local_addr()?);
+                    self.socket = Some(socket);
+                }
+
                 trace!("Created new channel to {:?}", addr);
                 self.channel = Channel::new(
                                 &self.token.client_to_server_key,
@@ -107,24 +182,28 @@ impl<I,S> ClientData<I,S> where I: SocketProvider<I,S> {
                                 addr,
                                 self.token.protocol,
                                 0,
-                                0)
+                                0,
+                                self.token.timeout_sec,
+                                self.time)
             },
             None => ()
         }
+
+        Ok(())
     }
 
     fn begin_host_connect(&mut self, idx: usize) -> Result<Option<ClientEvent>, SendError> {
-        self.connect_channel(idx);
+        self.connect_channel(idx)?;
         self.send_connect_token()?;
         self.ext_state = State::SendingConnectionRequest;
 
         Ok(Some(ClientEvent::NewState(self.ext_state.clone())))
     }
 
-    fn handle_payload(&mut self, packet: &packet::Packet, new_state: &mut Option<InternalState>) -> Result<Option<ClientEvent>, UpdateError> {
+    fn handle_payload(&mut self, sequence: u64, packet: &packet::Packet, new_state: &mut Option<InternalState>) -> Result<Option<ClientEvent>, UpdateError> {
         match packet {
             &packet::Packet::Payload(len) => {
-                Ok(Some(ClientEvent::Packet(len)))
+                Ok(Some(ClientEvent::Packet(len, sequence)))
             }
             &packet::Packet::Disconnect => {
                 *new_state = Some(InternalState::Disconnected);
@@ -156,19 +235,26 @@ impl<I,S> ClientData<I,S> where I: SocketProvider<I,S> {
                     Ok(None)
                 }
             },
-            &packet::Packet::KeepAlive(_) => match state {
+            &packet::Packet::KeepAlive(ref keep_alive) => match state {
                 &ConnectSequence::SendingToken => {
                     trace!("Got keep-alive while sending token, ignoring");
                     Ok(None)
                 }
                 &ConnectSequence::SendingChallenge(_,_) => {
                     trace!("Got keep-alive while sending challenge, connection established");
+                    self.client_idx = Some(keep_alive.client_idx as usize);
+                    self.max_clients = Some(keep_alive.max_clients as usize);
+
                     *new_state = Some(InternalState::Connected);
                     self.ext_state = State::Connected;
 
                     Ok(Some(ClientEvent::NewState(self.ext_state.clone())))
                 }
             },
+            &packet::Packet::ConnectionDenied => {
+                trace!("Connection denied by {:?}", self.channel.get_addr());
+                self.connect_next_host(idx, State::ConnectionDenied, new_state)
+            },
             p => {
                 trace!("Unexpected packet type {}, ignoring", p.get_type_id());
                 Ok(None)
@@ -179,7 +265,15 @@ impl<I,S> ClientData<I,S> where I: SocketProvider<I,S> {
     fn send_connect_token(&mut self) -> Result<usize, SendError> {
         let packet = packet::ConnectionRequestPacket::from_token(&self.token);
         
-        self.channel.send(self.time, &packet::Packet::ConnectionRequest(packet), None, &mut self.socket)
+        self.send_packet(&packet::Packet::ConnectionRequest(packet), None)
+    }
+
+    fn send_disconnect(&mut self) -> Result<(), SendError> {
+        for _ in 0..NETCODE_NUM_DISCONNECT_PACKETS {
+            self.send_packet(&packet::Packet::Disconnect, None)?;
+        }
+
+        Ok(())
     }
 
     fn send_challenge_token(&mut self, sequence: u64, token: &[u8; NETCODE_CHALLENGE_TOKEN_BYTES]) -> Result<usize, SendError> {
@@ -188,49 +282,137 @@ impl<I,S> ClientData<I,S> where I: SocketProvider<I,S> {
             token_data: *token.clone()
         };
 
-        self.channel.send(self.time, &packet::Packet::Response(packet), None, &mut self.socket)
+        self.send_packet(&packet::Packet::Response(packet), None)
     }
 }
 
-impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
-    /// Constructs a new client from an existing `ConnectToken`.
-    pub fn new(token: &ConnectToken) -> Result<Client<I,S>, SendError> {
-        Self::new_with_state(token, I::new_state())
+impl<T> Client<T> where T: BindTransport {
+    /// Constructs a new client from an existing `ConnectToken`. The client binds to `0.0.0.0:0` or `[::]:0`
+    /// depending on the address family of the host it is connecting to.
+    pub fn new(token: &ConnectToken) -> Result<Client<T>, SendError> {
+        let ipv6 = token.hosts.get().next().map_or(false, |host| host.is_ipv6());
+        let socket = T::bind(&any_addr(ipv6))?;
+
+        Self::new_internal(token, socket, Some(T::bind))
     }
 
-    fn new_with_state(token: &ConnectToken, mut socket_state: S) -> Result<Client<I,S>, SendError> {
-        use std::str::FromStr;
+    /// Constructs a new client from an existing `ConnectToken` bound to `bind_addr`.
+    /// Hosts in the token that can't be reached from `bind_addr` will fail to connect.
+    pub fn new_with_bind_addr(token: &ConnectToken, bind_addr: &SocketAddr) -> Result<Client<T>, SendError> {
+        Self::new_internal(token, T::bind(bind_addr)?, None)
+    }
+}
 
-        let local_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
-        let socket = I::bind(&local_addr, &mut socket_state)?;
+impl<T> Client<T> where T: Transport {
+    /// Constructs a new client from an existing `ConnectToken` that sends over a custom `transport`, see [Transport](trait.Transport.html).
+    pub fn with_transport(token: &ConnectToken, transport: T) -> Result<Client<T>, SendError> {
+        Self::new_internal(token, transport, None)
+    }
 
+    fn new_internal(token: &ConnectToken, socket: T, rebind: Option<fn(&SocketAddr) -> Result<T, io::Error>>) -> Result<Client<T>, SendError> {
         trace!("Client created on socket {:?}", socket.local_addr().unwrap());
 
+        let failed_state = if token.hosts.get().len() == 0 || token.expire_utc < token.create_utc {
+            info!("Connect token is invalid");
+            Some(State::InvalidConnectToken)
+        } else if token::get_time_now() > token.expire_utc {
+            info!("Connect token has expired");
+            Some(State::ConnectTokenExpired)
+        } else {
+            None
+        };
+
         let channel = Channel::new(
             &token.client_to_server_key,
             &token.server_to_client_key,
-            &token.hosts.get().next().unwrap(),
+            &token.hosts.get().next().unwrap_or(any_addr(false)),
             token.protocol,
             0,
-            0);
+            0,
+            token.timeout_sec,
+            0.0);
 
         let mut data = ClientData {
                 time: 0.0,
                 ext_state: State::SendingConnectionRequest,
                 channel: channel,
-                socket: socket,
-                socket_state: socket_state,
-                token: token.clone()
+                socket: Some(socket),
+                rebind: rebind,
+                token: token.clone(),
+                token_lifetime: token.expire_utc.saturating_sub(token::get_time_now()) as f64,
+                client_idx: None,
+                max_clients: None,
+                pending_events: VecDeque::new()
             };
 
-        data.begin_host_connect(0)?;
+        let state = match failed_state {
+            Some(state) => {
+                data.ext_state = state;
+                data.pending_events.push_back(ClientEvent::NewState(data.ext_state.clone()));
+
+                InternalState::Disconnected
+            },
+            None => {
+                data.begin_host_connect(0)?;
+
+                InternalState::Connecting(0, ConnectSequence::SendingToken)
+            }
+        };
 
         Ok(Client {
-            state: InternalState::Connecting(0, ConnectSequence::SendingToken),
+            state: state,
             data: data
         })
     }
 
+    /// Constructs a client connected to a loopback slot on a local server, see `Server::connect_loopback_client(..)`.
+    /// The client starts out connected and exchanges payloads with the server through in-memory queues, so no transport is needed.
+    pub fn new_loopback(endpoint: LoopbackEndpoint) -> Client<T> {
+        trace!("Loopback client created in slot {}", endpoint.client_index());
+
+        //Loopback clients never handshake so the token is only a placeholder
+        let token = ConnectToken {
+            protocol: 0,
+            create_utc: 0,
+            expire_utc: 0,
+            sequence: 0,
+            private_data: [0; NETCODE_CONNECT_TOKEN_PRIVATE_BYTES],
+            hosts: HostList::new(::std::iter::empty()),
+            client_to_server_key: [0; NETCODE_KEY_BYTES],
+            server_to_client_key: [0; NETCODE_KEY_BYTES],
+            timeout_sec: 0
+        };
+
+        let channel = Channel::new(
+            &token.client_to_server_key,
+            &token.server_to_client_key,
+            &any_addr(false),
+            token.protocol,
+            endpoint.client_index(),
+            endpoint.max_clients(),
+            token.timeout_sec,
+            0.0);
+
+        let mut pending_events = VecDeque::new();
+        pending_events.push_back(ClientEvent::NewState(State::Connected));
+
+        Client {
+            data: ClientData {
+                time: 0.0,
+                ext_state: State::Connected,
+                channel: channel,
+                socket: None,
+                rebind: None,
+                token: token,
+                token_lifetime: 0.0,
+                client_idx: Some(endpoint.client_index()),
+                max_clients: Some(endpoint.max_clients()),
+                pending_events: pending_events
+            },
+            state: InternalState::Loopback(endpoint)
+        }
+    }
+
     /// Updates time elapsed since last client iteration.
     pub fn update(&mut self, elapsed: f64) -> Result<(), UpdateError> {
         self.data.time += elapsed;
@@ -241,27 +423,66 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
     /// Checks for incoming packets and state changes. Returns `None` when no more events
     /// are pending.
     pub fn next_event(&mut self, payload: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Option<ClientEvent>, UpdateError> {
+        if let Some(event) = self.data.pending_events.pop_front() {
+            return Ok(Some(event))
+        }
+
+        //Loopback clients only talk to their queues
+        let loopback_disconnected = match self.state {
+            InternalState::Loopback(ref endpoint) => {
+                if let Some((len, sequence)) = endpoint.recv(payload) {
+                    return Ok(Some(ClientEvent::Packet(len, sequence)))
+                }
+
+                if !endpoint.is_disconnected() {
+                    return Ok(None)
+                }
+
+                true
+            },
+            _ => false
+        };
+
+        if loopback_disconnected {
+            info!("Loopback client disconnected by server");
+
+            self.state = InternalState::Disconnected;
+            self.data.ext_state = State::Disconnected;
+
+            return Ok(Some(ClientEvent::NewState(self.data.ext_state.clone())))
+        }
+
         let mut new_state = None;
 
         let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
-        let socket_result = match self.data.socket.recv_from(&mut scratch[..]) {
-            Ok((len, addr)) => {
+        let socket_result = match self.data.socket.as_mut().map(|socket| socket.recv_from(&mut scratch[..])) {
+            Some(Ok((len, addr))) => {
                 if addr == *self.data.channel.get_addr() {
-                    self.data.channel.recv(self.data.time, &scratch[..len], payload).map(|p| Some(p))?
+                    match self.data.channel.recv(self.data.time, &scratch[..len], payload) {
+                        Ok(packet) => Some(packet),
+                        //Networks can duplicate packets, replay protection already threw it away so it's safe to ignore
+                        Err(RecvError::DuplicateSequence) => {
+                            trace!("Discarded duplicate packet from {:?}", addr);
+                            None
+                        },
+                        Err(e) => return Err(e.into())
+                    }
                 } else {
                     trace!("Discarded packet from unknown host {:?}", addr);
                     None
                 }
             },
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
-            Err(e) => return Err(RecvError::SocketError(e).into())
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Some(Err(e)) => return Err(RecvError::SocketError(e).into()),
+            None => None
         };
 
         //If we have any socket data process that first
-        let socket_process = if let Some(packet) = socket_result {
+        let socket_process = if let Some((sequence, packet)) = socket_result {
             match &mut self.state {
                 &mut InternalState::Connecting(idx, ref req) => self.data.handle_response(&packet, req, &mut new_state, idx),
-                &mut InternalState::Connected => self.data.handle_payload(&packet, &mut new_state),
+                &mut InternalState::Connected => self.data.handle_payload(sequence, &packet, &mut new_state),
+                &mut InternalState::Loopback(_) |
                 &mut InternalState::Disconnected => Ok(None)
             }
         } else {
@@ -272,20 +493,19 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
             //If we didn't get a packet, see if there's some upkeep to do
             Ok(None) => {
                 match &mut self.state {
-                    &mut InternalState::Connecting(mut idx, ref req) => {
+                    &mut InternalState::Connecting(idx, ref req) => {
+                        if self.data.token_expired() {
+                            info!("Connect token expired while connecting");
+                            self.data.disconnect(State::ConnectTokenExpired, &mut new_state)
+                        } else {
                             match self.data.update_channel(false)? {
                                 channel::UpdateResult::Expired => {
-                                    idx += 1;
-
-                                    if idx >= self.data.token.hosts.get().len() {
-                                        info!("Failed to connect to last host, disconnecting");
-                                        self.data.disconnect(&mut new_state)
-                                    } else {
-                                        trace!("Failed to connect to host {:?}, moving to next host", self.data.channel.get_addr());
+                                    let failed_state = match req {
+                                        &ConnectSequence::SendingToken => State::ConnectionRequestTimedOut,
+                                        &ConnectSequence::SendingChallenge(_,_) => State::ConnectionResponseTimedOut
+                                    };
 
-                                        self.data.begin_host_connect(idx)
-                                            .map_err(|e| e.into())
-                                    }
+                                    self.data.connect_next_host(idx, failed_state, &mut new_state)
                                 },
                                 channel::UpdateResult::SentKeepAlive => {
                                     let send = match req {
@@ -297,14 +517,19 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
                                 },
                                 channel::UpdateResult::Noop => Ok(None)
                             }
+                        }
                     },
                     &mut InternalState::Connected => {
                         match self.data.update_channel(true)? {
-                            channel::UpdateResult::Expired => self.data.disconnect(&mut new_state),
+                            channel::UpdateResult::Expired => {
+                                info!("Connection to {:?} timed out", self.data.channel.get_addr());
+                                self.data.disconnect(State::ConnectionTimedOut, &mut new_state)
+                            },
                             channel::UpdateResult::SentKeepAlive => Ok(Some(ClientEvent::SentKeepAlive)),
                             channel::UpdateResult::Noop => Ok(None)
                         }
                     },
+                    &mut InternalState::Loopback(_) |
                     &mut InternalState::Disconnected => Ok(None)
                 }
             },
@@ -312,6 +537,11 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
         };
 
         if let Some(state) = new_state {
+            match state {
+                InternalState::Connected | InternalState::Loopback(_) => (),
+                _ => self.data.clear_client_index()
+            }
+
             self.state = state;
         }
 
@@ -320,17 +550,47 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
 
     /// Sends a packet to connected server.
     pub fn send(&mut self, payload: &[u8]) -> Result<usize, SendError> {
-        match payload.len() {
-            0 | NETCODE_MAX_PAYLOAD_SIZE => return Err(SendError::PacketSize),
-            _ => ()
+        if payload.len() == 0 || payload.len() > NETCODE_MAX_PAYLOAD_SIZE {
+            return Err(SendError::PacketSize)
         }
 
         match self.state {
-            InternalState::Disconnected => return Err(SendError::Disconnected),
-            _ => ()
+            InternalState::Disconnected => Err(SendError::Disconnected),
+            InternalState::Loopback(ref endpoint) => endpoint.send(payload).map(|_| payload.len()),
+            _ => self.data.send_packet(&packet::Packet::Payload(payload.len()), Some(payload))
+        }
+    }
+
+    /// Sends any packets held back by a batching transport such as `BatchUdpSocket`, call once per frame after sending payloads and
+    /// draining `next_event(..)`. Does nothing for transports that send right away.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        match self.data.socket {
+            Some(ref mut socket) => socket.flush(),
+            None => Ok(())
         }
+    }
 
-        self.data.channel.send(self.data.time, &packet::Packet::Payload(payload.len()), Some(payload), &mut self.data.socket)
+    /// Disconnects from the server, sending redundant disconnect packets so the server can free our slot right away.
+    /// `ClientEvent::NewState(State::Disconnected)` is returned from the next call to `next_event(..)`.
+    pub fn disconnect(&mut self) -> Result<(), SendError> {
+        let result = match self.state {
+            InternalState::Disconnected => return Err(SendError::Disconnected),
+            InternalState::Connected => self.data.send_disconnect(),
+            InternalState::Loopback(ref endpoint) => {
+                endpoint.disconnect();
+                Ok(())
+            },
+            InternalState::Connecting(_,_) => Ok(())
+        };
+
+        trace!("Disconnecting from {:?}", self.data.channel.get_addr());
+
+        self.state = InternalState::Disconnected;
+        self.data.ext_state = State::Disconnected;
+        self.data.clear_client_index();
+        self.data.pending_events.push_back(ClientEvent::NewState(self.data.ext_state.clone()));
+
+        result
     }
 
     /// Gets the current state of our client.
@@ -338,14 +598,100 @@ impl<I,S> Client<I,S> where I: SocketProvider<I,S> {
         self.data.ext_state.clone()
     }
 
+    /// Gets the sequence number that will be used for the next packet sent to the server.
+    pub fn next_packet_sequence(&self) -> u64 {
+        match self.state {
+            InternalState::Loopback(ref endpoint) => endpoint.get_next_sequence(),
+            _ => self.data.channel.get_next_sequence()
+        }
+    }
+
+    /// Returns true if this client is connected to a loopback slot, see `new_loopback(..)`.
+    pub fn is_loopback(&self) -> bool {
+        match self.state {
+            InternalState::Loopback(_) => true,
+            _ => false
+        }
+    }
+
+    /// Gets the number of seconds until a handshake resend, keep-alive or timeout is due, `None` if the client is disconnected or loopback.
+    ///
+    /// Event loops can sleep until the socket is readable or this much time has passed(rounding up) before calling `update(..)`
+    /// and `next_event(..)`.
+    pub fn next_deadline(&self) -> Option<f64> {
+        if !self.data.pending_events.is_empty() {
+            return Some(0.0)
+        }
+
+        let deadline = match self.state {
+            InternalState::Connecting(_,_) => {
+                self.data.channel.next_deadline().min(self.data.token_lifetime)
+            },
+            InternalState::Connected => self.data.channel.next_deadline(),
+            InternalState::Loopback(_) | InternalState::Disconnected => return None
+        };
+
+        Some((deadline - self.data.time).max(0.0))
+    }
+
+    /// Gets the slot index the server assigned us, `None` until the server's first keep-alive arrives.
+    pub fn client_index(&self) -> Option<usize> {
+        self.data.client_idx
+    }
+
+    /// Gets the number of client slots on the server we're connected to, `None` until the server's first keep-alive arrives.
+    pub fn max_clients(&self) -> Option<usize> {
+        self.data.max_clients
+    }
+
+    /// Socket we're sending on, `None` for loopback clients. It may be replaced while connecting to a host of another address family.
+    #[cfg(feature = "async-tokio")]
+    pub(crate) fn transport(&self) -> Option<&T> {
+        self.data.socket.as_ref()
+    }
+
     #[cfg(test)]
     fn set_read_timeout(&mut self, duration: Option<Duration>) -> Result<(), io::Error> {
-        self.data.socket.set_recv_timeout(duration)
+        match self.data.socket {
+            Some(ref mut socket) => socket.set_recv_timeout(duration),
+            None => Ok(())
+        }
     }
-    
-    #[cfg(test)]
-    pub fn get_socket_state(&mut self) -> &mut S {
-        &mut self.data.socket_state
+}
+
+/// Raw fd of the client's socket.
+///
+/// The socket is replaced if the connect token mixes address families and we move on to a host of the other family,
+/// re-register after `ClientEvent::NewState(State::SendingConnectionRequest)`. Panics for loopback clients which have no socket.
+#[cfg(unix)]
+impl<T> AsRawFd for Client<T> where T: Transport + AsRawFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.data.socket.as_ref().expect("loopback clients don't have a socket").as_raw_fd()
+    }
+}
+
+/// Registers the client's socket, loopback clients have nothing to register. See `AsRawFd` for when the socket changes.
+#[cfg(all(unix, feature = "mio"))]
+impl<T> mio::event::Source for Client<T> where T: Transport + AsRawFd {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> Result<(), io::Error> {
+        match self.data.socket {
+            Some(ref socket) => SourceFd(&socket.as_raw_fd()).register(registry, token, interests),
+            None => Ok(())
+        }
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> Result<(), io::Error> {
+        match self.data.socket {
+            Some(ref socket) => SourceFd(&socket.as_raw_fd()).reregister(registry, token, interests),
+            None => Ok(())
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> Result<(), io::Error> {
+        match self.data.socket {
+            Some(ref socket) => SourceFd(&socket.as_raw_fd()).deregister(registry),
+            None => Ok(())
+        }
     }
 }
 
@@ -361,9 +707,9 @@ mod test {
     const MAX_CLIENTS: usize = 256;
     const CLIENT_ID: u64 = 0xFFEEDD;
 
-    struct TestHarness<I,S> where I: SocketProvider<I,S> {
-        client: Client<I,S>,
-        server: Option<Server<I,S>>
+    struct TestHarness<T> where T: Transport {
+        client: Client<T>,
+        server: Option<Server<T>>
     }
 
     
@@ -374,25 +720,30 @@ mod test {
 
         LogBuilder::new().filter(None, LogLevelFilter::Trace).init().unwrap();
 
-        use capi::*;
+        #[cfg(feature = "capi-tests")]
         unsafe {
+            use capi::*;
             netcode_log_level(NETCODE_LOG_LEVEL_DEBUG as i32);
         }
     }
 
-    impl<S,I> TestHarness<I,S> where I: SocketProvider<I,S>, S: Clone {
-        pub fn new(in_token: Option<ConnectToken>) -> TestHarness<I,S> {
+    impl<T> TestHarness<T> where T: BindTransport {
+        pub fn new(in_token: Option<ConnectToken>) -> TestHarness<T> {
+            Self::new_with_timeout(in_token, NETCODE_TIMEOUT_SECONDS)
+        }
+
+        pub fn new_with_timeout(in_token: Option<ConnectToken>, timeout_sec: u32) -> TestHarness<T> {
             let private_key = crypto::generate_key();
 
             let addr = format!("127.0.0.1:0");
             let (server, mut client) = if let Some(ref token) = in_token {
-                let client = Client::<I,S>::new_with_state(token, I::new_state()).unwrap();
+                let client = Client::<T>::new(token).unwrap();
                 (None, client)
             } else {
-                let mut server = Server::<I,S>::new(&addr, MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+                let mut server = Server::<T>::new(&addr, MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
                 server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
-                let token = Self::generate_connect_token(&private_key, server.get_local_addr().unwrap());
-                let client = Client::<I,S>::new_with_state(&token, server.get_socket_state().clone()).unwrap();
+                let token = Self::generate_connect_token(&private_key, server.get_local_addr().unwrap(), timeout_sec);
+                let client = Client::<T>::new(&token).unwrap();
                 (Some(server), client)
             };
 
@@ -405,11 +756,12 @@ mod test {
         }
 
 
-        pub fn generate_connect_token(private_key: &[u8; NETCODE_KEY_BYTES], addr: SocketAddr) -> token::ConnectToken {
-            token::ConnectToken::generate(
+        pub fn generate_connect_token(private_key: &[u8; NETCODE_KEY_BYTES], addr: SocketAddr, timeout_sec: u32) -> token::ConnectToken {
+            token::ConnectToken::generate_with_timeout(
                                 [addr].iter().cloned(),
                                 private_key,
                                 30, //Expire
+                                timeout_sec,
                                 0,
                                 PROTOCOL_ID,
                                 CLIENT_ID, //Client Id
@@ -435,7 +787,7 @@ mod test {
  
     #[test]
     fn test_client_connect() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new(None);
 
         match harness.client.get_state() {
             State::SendingConnectionRequest => (),
@@ -455,9 +807,858 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_disconnect() {
+        let mut harness = TestHarness::<UdpSocket>::new(None);
+
+        harness.update_server();
+        harness.update_client().unwrap();
+
+        harness.update_server();
+        match harness.update_client().unwrap() {
+            ClientEvent::NewState(State::Connected) => (),
+            s => assert!(false, "{:?}", s)
+        }
+
+        harness.client.disconnect().unwrap();
+        assert_eq!(harness.client.client_index(), None);
+        assert_eq!(harness.client.max_clients(), None);
+
+        match harness.update_client().unwrap() {
+            ClientEvent::NewState(State::Disconnected) => (),
+            s => assert!(false, "{:?}", s)
+        }
+
+        match harness.update_server() {
+            Some(ServerEvent::ClientDisconnect(CLIENT_ID)) => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        match harness.client.send(&[0; 16]) {
+            Err(SendError::Disconnected) => (),
+            r => assert!(false, "{:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_connection_denied() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", 1, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+        //Take the only slot
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut first = UdpClient::new(&token).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        server.update(0.0).unwrap();
+        assert!(server.next_event(&mut scratch).unwrap().is_none());
+        first.update(0.0).unwrap();
+        first.next_event(&mut scratch).unwrap();
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(CLIENT_ID)) => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        //Both hosts deny us so we should try each before giving up
+        let token = ConnectToken::generate([addr, addr].iter().cloned(), &private_key, 30, 1, PROTOCOL_ID, CLIENT_ID + 1, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        for state in [State::SendingConnectionRequest, State::ConnectionDenied].iter() {
+            server.update(0.0).unwrap();
+            match server.next_event(&mut scratch).unwrap() {
+                Some(ServerEvent::ClientSlotFull) => (),
+                e => assert!(false, "{:?}", e)
+            }
+
+            client.update(0.0).unwrap();
+            match (client.next_event(&mut scratch).unwrap(), state) {
+                (Some(ClientEvent::NewState(State::SendingConnectionRequest)), &State::SendingConnectionRequest) |
+                (Some(ClientEvent::NewState(State::ConnectionDenied)), &State::ConnectionDenied) => (),
+                (e, _) => assert!(false, "{:?}", e)
+            }
+        }
+
+        match client.get_state() {
+            State::ConnectionDenied => (),
+            s => assert!(false, "{:?}", s)
+        }
+    }
+
+    #[test]
+    fn test_pending_does_not_hold_slot() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", 1, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut first = UdpClient::new(&token).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 1, PROTOCOL_ID, CLIENT_ID + 1, None).unwrap();
+        let mut second = UdpClient::new(&token).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        //Both requests are challenged even though there's only one slot
+        for _ in 0..2 {
+            server.update(0.0).unwrap();
+            assert!(server.next_event(&mut scratch).unwrap().is_none());
+        }
+
+        //Whoever responds first gets the slot
+        expect_state(&mut second, 0.0, State::SendingConnectionResponse);
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(id)) if id == CLIENT_ID + 1 => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut second, 0.0, State::Connected);
+        assert_eq!(second.client_index(), Some(0));
+        assert_eq!(second.max_clients(), Some(1));
+
+        expect_state(&mut first, 0.0, State::SendingConnectionResponse);
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientSlotFull) => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut first, 0.0, State::ConnectionDenied);
+    }
+
+    #[test]
+    fn test_slot_reuse() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", 3, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut clients = vec!();
+        for i in 0..3 {
+            let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, i, PROTOCOL_ID, CLIENT_ID + i, None).unwrap();
+            let mut client = UdpClient::new(&token).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            connect_to(&mut server, &mut client, CLIENT_ID + i);
+            assert_eq!(client.client_index(), Some(i as usize));
+            clients.push(client);
+        }
+
+        //Freed slot goes to the next client that connects
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        server.disconnect_client(CLIENT_ID + 1).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientDisconnect(id)) if id == CLIENT_ID + 1 => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut clients[1], 0.0, State::Disconnected);
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 3, PROTOCOL_ID, CLIENT_ID + 3, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        connect_to(&mut server, &mut client, CLIENT_ID + 3);
+        assert_eq!(client.client_index(), Some(1));
+
+        //Sends still reach the right client after the slot changed hands
+        server.send(CLIENT_ID + 3, &[3; 16]).unwrap();
+        server.send(CLIENT_ID + 2, &[2; 16]).unwrap();
+        for &mut (ref mut client, value) in [(&mut client, 3), (&mut clients[2], 2)].iter_mut() {
+            //Skip over any keep-alives queued ahead of the payload
+            let received = (0..8).any(|_| {
+                client.update(0.0).unwrap();
+                match client.next_event(&mut scratch) {
+                    Ok(Some(ClientEvent::Packet(16, _))) => true,
+                    Ok(None) => false,
+                    e => panic!("{:?}", e)
+                }
+            });
+            assert!(received);
+            assert_eq!(scratch[..16], [value; 16]);
+        }
+        assert!(server.send(CLIENT_ID + 1, &[1; 16]).is_err());
+    }
+
+    #[test]
+    fn test_server_sender() {
+        use std::thread;
+
+        const PAYLOADS: usize = 32;
+
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", 2, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut clients = vec!();
+        for i in 0..2 {
+            let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, i, PROTOCOL_ID, CLIENT_ID + i, None).unwrap();
+            let mut client = UdpClient::new(&token).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            connect_to(&mut server, &mut client, CLIENT_ID + i);
+            clients.push(client);
+        }
+
+        //Sender thread and the server send to the same clients at once, sharing sequence numbers
+        let mut sender = server.sender().unwrap();
+        let mut thread_sender = sender.try_clone().unwrap();
+        let thread = thread::spawn(move || {
+            for i in 0..PAYLOADS {
+                for id in 0..2 {
+                    thread_sender.send(CLIENT_ID + id, &[1, i as u8]).unwrap();
+                }
+            }
+        });
+
+        for i in 0..PAYLOADS {
+            for id in 0..2 {
+                server.send(CLIENT_ID + id, &[0, i as u8]).unwrap();
+            }
+        }
+        thread.join().unwrap();
+
+        assert_eq!(server.next_packet_sequence(CLIENT_ID), sender.next_packet_sequence(CLIENT_ID));
+
+        //Duplicate sequence numbers would be dropped by the client's replay protection
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        for client in clients.iter_mut() {
+            let mut received = [0; 2];
+            for _ in 0..PAYLOADS * 8 {
+                if received == [PAYLOADS, PAYLOADS] {
+                    break
+                }
+
+                client.update(0.0).unwrap();
+                match client.next_event(&mut scratch) {
+                    Ok(Some(ClientEvent::Packet(2, _))) => received[scratch[0] as usize] += 1,
+                    Ok(None) => (),
+                    e => assert!(false, "{:?}", e)
+                }
+            }
+            assert_eq!(received, [PAYLOADS, PAYLOADS]);
+        }
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        server.disconnect_client(CLIENT_ID).unwrap();
+        server.next_event(&mut scratch).unwrap();
+        assert!(!sender.is_connected(CLIENT_ID));
+        assert!(sender.is_connected(CLIENT_ID + 1));
+        match sender.send(CLIENT_ID, &[0]) {
+            Err(SendError::InvalidClientId) => (),
+            r => assert!(false, "{:?}", r)
+        }
+
+        //Sends from other threads push back the server's next keep alive
+        server.update(0.09).unwrap();
+        assert!((server.next_deadline().unwrap() - 0.01).abs() < 1e-9);
+        sender.send(CLIENT_ID + 1, &[0]).unwrap();
+        server.update(0.0).unwrap();
+        assert!((server.next_deadline().unwrap() - channel::KEEPALIVE_RETRY).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", 4, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut clients = vec!();
+        for i in 0..2 {
+            let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, i, PROTOCOL_ID, CLIENT_ID + i, None).unwrap();
+            let mut client = UdpClient::new(&token).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            connect_to(&mut server, &mut client, CLIENT_ID + i);
+            clients.push(client);
+        }
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let endpoint = server.connect_loopback_client(2, CLIENT_ID + 2, None).unwrap();
+        clients.push(UdpClient::new_loopback(endpoint));
+        server.next_event(&mut scratch).unwrap();
+        expect_state(&mut clients[2], 0.0, State::Connected);
+
+        fn expect_payload(client: &mut UdpClient, expected: Option<u8>) {
+            let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+            let mut received = None;
+            for _ in 0..4 {
+                client.update(0.0).unwrap();
+                if let Some(ClientEvent::Packet(1, _)) = client.next_event(&mut scratch).unwrap() {
+                    received = Some(scratch[0]);
+                    break
+                }
+
+                //Loopback queues are either ready or empty
+                if client.is_loopback() {
+                    break
+                }
+            }
+
+            assert_eq!(received, expected);
+        }
+
+        let results = server.broadcast(&[1]).unwrap();
+        assert_eq!(results.iter().map(|&(id, ref r)| (id, r.is_ok())).collect::<Vec<_>>(),
+            vec!((CLIENT_ID, true), (CLIENT_ID + 1, true), (CLIENT_ID + 2, true)));
+        for client in clients.iter_mut() {
+            expect_payload(client, Some(1));
+        }
+
+        let results = server.broadcast_except(&[CLIENT_ID + 1], &[2]).unwrap();
+        assert_eq!(results.iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec!(CLIENT_ID, CLIENT_ID + 2));
+        expect_payload(&mut clients[0], Some(2));
+        expect_payload(&mut clients[2], Some(2));
+
+        let results = server.send_to_many(&[CLIENT_ID + 2, CLIENT_ID + 5, CLIENT_ID + 1], &[3]).unwrap();
+        match results[1] {
+            (id, Err(SendError::InvalidClientId)) if id == CLIENT_ID + 5 => (),
+            ref r => assert!(false, "{:?}", r)
+        }
+        assert!(results[0].1.is_ok() && results[2].1.is_ok());
+        expect_payload(&mut clients[2], Some(3));
+        expect_payload(&mut clients[1], Some(3));
+        clients[0].set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        expect_payload(&mut clients[0], None);
+
+        match server.broadcast(&[]) {
+            Err(SendError::PacketSize) => (),
+            r => assert!(false, "{:?}", r)
+        }
+    }
+
+    fn connect_to<T>(server: &mut Server<T>, client: &mut Client<T>, client_id: u64) where T: Transport {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+        server.update(0.0).unwrap();
+        assert!(server.next_event(&mut scratch).unwrap().is_none());
+        expect_state(client, 0.0, State::SendingConnectionResponse);
+
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(id)) if id == client_id => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(client, 0.0, State::Connected);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("[::1]:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        connect_to(&mut server, &mut client, CLIENT_ID);
+    }
+
+    #[test]
+    fn test_bind_addr() {
+        use std::str::FromStr;
+
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = UdpClient::new_with_bind_addr(&token, &SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        connect_to(&mut server, &mut client, CLIENT_ID);
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new_dual_stack("127.0.0.1:0", "[::1]:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let addrs = server.get_local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+
+        for (i, addr) in addrs.iter().enumerate() {
+            let client_id = CLIENT_ID + i as u64;
+            let token = ConnectToken::generate([addr.clone()].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, client_id, None).unwrap();
+            let mut client = UdpClient::new(&token).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+            connect_to(&mut server, &mut client, client_id);
+        }
+    }
+
+    #[test]
+    fn test_loopback_payload_size() {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        let endpoint = server.connect_loopback_client(0, CLIENT_ID, None).unwrap();
+        let mut loopback = UdpClient::new_loopback(endpoint);
+        expect_state(&mut loopback, 0.0, State::Connected);
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(id)) if id == CLIENT_ID => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        let max = [0xCD; NETCODE_MAX_PAYLOAD_SIZE + 1];
+        assert_eq!(loopback.send(&max[..NETCODE_MAX_PAYLOAD_SIZE]).unwrap(), NETCODE_MAX_PAYLOAD_SIZE);
+        match loopback.send(&max) {
+            Err(SendError::PacketSize) => (),
+            r => assert!(false, "{:?}", r)
+        }
+
+        //Only the valid payload reaches the server
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::Packet(id, len, _)) if id == CLIENT_ID => assert_eq!(len, NETCODE_MAX_PAYLOAD_SIZE),
+            e => assert!(false, "{:?}", e)
+        }
+        assert!(server.next_event(&mut scratch).unwrap().is_none());
+
+        assert_eq!(server.send(CLIENT_ID, &max[..NETCODE_MAX_PAYLOAD_SIZE]).unwrap(), NETCODE_MAX_PAYLOAD_SIZE);
+        match server.send(CLIENT_ID, &max) {
+            Err(SendError::PacketSize) => (),
+            r => assert!(false, "{:?}", r)
+        }
+        match loopback.next_event(&mut scratch).unwrap() {
+            Some(ClientEvent::Packet(len, _)) => assert_eq!(len, NETCODE_MAX_PAYLOAD_SIZE),
+            e => assert!(false, "{:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_dual_stack_wildcard() {
+        //Bind the wildcard addresses, then advertise loopback on whichever ports we got in the tokens
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new_dual_stack_with_public_addrs(
+            "0.0.0.0:0", "[::]:0", "127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let local_addrs = server.get_local_addrs().unwrap();
+        assert!(local_addrs.iter().all(|addr| addr.ip().is_unspecified()));
+
+        let public_addrs = local_addrs.iter()
+            .map(|addr| match addr {
+                &SocketAddr::V4(_) => SocketAddr::new("127.0.0.1".parse().unwrap(), addr.port()),
+                &SocketAddr::V6(_) => SocketAddr::new("::1".parse().unwrap(), addr.port())
+            })
+            .collect::<Vec<_>>();
+        server.set_public_addrs(public_addrs.clone());
+
+        for (i, addr) in public_addrs.iter().enumerate() {
+            let client_id = CLIENT_ID + i as u64;
+            let token = ConnectToken::generate([addr.clone()].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, client_id, None).unwrap();
+            let mut client = UdpClient::new(&token).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+            connect_to(&mut server, &mut client, client_id);
+        }
+    }
+
+    #[test]
+    fn test_with_transport() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = Client::with_transport(&token, UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        connect_to(&mut server, &mut client, CLIENT_ID);
+    }
+
+    #[test]
+    fn test_mixed_family() {
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        //Nothing is listening on the IPv6 host so we should fail over to the IPv4 one
+        let unused = UdpSocket::bind("[::1]:0").unwrap().local_addr().unwrap();
+        let token = ConnectToken::generate([unused, addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        expect_state(&mut client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::SendingConnectionRequest);
+
+        //Socket was rebound for the new family
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        connect_to(&mut server, &mut client, CLIENT_ID);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let user_data = [0xAB; NETCODE_USER_DATA_BYTES];
+        let endpoint = server.connect_loopback_client(0, CLIENT_ID, Some(&user_data)).unwrap();
+        let mut loopback = UdpClient::new_loopback(endpoint);
+        assert!(loopback.is_loopback());
+        assert!(server.is_loopback_client(CLIENT_ID));
+
+        match server.connect_loopback_client(0, CLIENT_ID + 1, None) {
+            Err(LoopbackError::SlotInUse) => (),
+            e => assert!(false, "{:?}", e.map(|_| ()))
+        }
+        match server.connect_loopback_client(1, CLIENT_ID, None) {
+            Err(LoopbackError::ClientIdInUse) => (),
+            e => assert!(false, "{:?}", e.map(|_| ()))
+        }
+        match server.connect_loopback_client(MAX_CLIENTS, CLIENT_ID + 1, None) {
+            Err(LoopbackError::InvalidClientIndex) => (),
+            e => assert!(false, "{:?}", e.map(|_| ()))
+        }
+
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(id)) if id == CLIENT_ID => assert_eq!(&scratch[..NETCODE_USER_DATA_BYTES], &user_data[..]),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut loopback, 0.0, State::Connected);
+        assert_eq!(loopback.client_index(), Some(0));
+        assert_eq!(loopback.max_clients(), Some(MAX_CLIENTS));
+
+        //Network clients take the next free slot
+        let addr = server.get_local_addr().unwrap();
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID + 1, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        connect_to(&mut server, &mut client, CLIENT_ID + 1);
+        assert_eq!(client.client_index(), Some(1));
+
+        for i in 0..3 {
+            let sequence = loopback.next_packet_sequence();
+            assert_eq!(loopback.send(&[i; 4]).unwrap(), 4);
+            match server.next_event(&mut scratch).unwrap() {
+                Some(ServerEvent::Packet(id, 4, seq)) if id == CLIENT_ID && seq == sequence => assert_eq!(&scratch[..4], &[i; 4]),
+                e => assert!(false, "{:?}", e)
+            }
+
+            let sequence = server.next_packet_sequence(CLIENT_ID).unwrap();
+            assert_eq!(server.send(CLIENT_ID, &[i; 8]).unwrap(), 8);
+            match loopback.next_event(&mut scratch).unwrap() {
+                Some(ClientEvent::Packet(8, seq)) if seq == sequence => assert_eq!(&scratch[..8], &[i; 8]),
+                e => assert!(false, "{:?}", e)
+            }
+        }
+
+        //Loopback clients don't time out or send keep alives
+        loopback.update((NETCODE_TIMEOUT_SECONDS + 1) as f64).unwrap();
+        assert!(loopback.next_event(&mut scratch).unwrap().is_none());
+        server.set_read_timeout(None).unwrap();
+        server.update((NETCODE_TIMEOUT_SECONDS + 1) as f64).unwrap();
+        loop {
+            match server.next_event(&mut scratch).unwrap() {
+                Some(ServerEvent::ClientDisconnect(id)) => assert_eq!(id, CLIENT_ID + 1),
+                Some(ServerEvent::KeepAlive(id)) => assert_eq!(id, CLIENT_ID + 1),
+                None => break,
+                e => assert!(false, "{:?}", e)
+            }
+        }
+        assert!(server.is_loopback_client(CLIENT_ID));
+
+        //Client disconnect frees the slot
+        loopback.disconnect().unwrap();
+        expect_state(&mut loopback, 0.0, State::Disconnected);
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientDisconnect(id)) if id == CLIENT_ID => (),
+            e => assert!(false, "{:?}", e)
+        }
+        assert!(loopback.send(&[0; 4]).is_err());
+
+        //Server disconnect is seen by the client
+        let endpoint = server.connect_loopback_client(0, CLIENT_ID, None).unwrap();
+        let mut loopback = UdpClient::new_loopback(endpoint);
+        expect_state(&mut loopback, 0.0, State::Connected);
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(id)) if id == CLIENT_ID => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        server.disconnect_client(CLIENT_ID).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientDisconnect(id)) if id == CLIENT_ID => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut loopback, 0.0, State::Disconnected);
+    }
+
+    #[test]
+    fn test_next_deadline() {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(server.next_deadline(), None);
+
+        let addr = server.get_local_addr().unwrap();
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        assert_eq!(client.next_deadline(), Some(channel::KEEPALIVE_RETRY));
+
+        //Challenged request times out if the client never responds
+        server.update(0.0).unwrap();
+        assert!(server.next_event(&mut scratch).unwrap().is_none());
+        assert_eq!(server.next_deadline(), Some(NETCODE_TIMEOUT_SECONDS as f64));
+
+        expect_state(&mut client, 0.0, State::SendingConnectionResponse);
+        server.update(0.0).unwrap();
+        match server.next_event(&mut scratch).unwrap() {
+            Some(ServerEvent::ClientConnect(CLIENT_ID)) => (),
+            e => assert!(false, "{:?}", e)
+        }
+        expect_state(&mut client, 0.0, State::Connected);
+        assert_eq!(server.next_deadline(), Some(channel::KEEPALIVE_RETRY));
+        assert_eq!(client.next_deadline(), Some(channel::KEEPALIVE_RETRY));
+
+        client.set_read_timeout(None).unwrap();
+        client.update(0.05).unwrap();
+        assert!(client.next_event(&mut scratch).unwrap().is_none());
+        assert!((client.next_deadline().unwrap() - 0.05).abs() < 1e-9);
+
+        //Keep alive goes out once the deadline is reached
+        client.update(0.05).unwrap();
+        assert_eq!(client.next_deadline(), Some(0.0));
+        match client.next_event(&mut scratch).unwrap() {
+            Some(ClientEvent::SentKeepAlive) => (),
+            e => assert!(false, "{:?}", e)
+        }
+        assert!((client.next_deadline().unwrap() - channel::KEEPALIVE_RETRY).abs() < 1e-9);
+
+        client.disconnect().unwrap();
+        expect_state(&mut client, 0.0, State::Disconnected);
+        assert_eq!(client.next_deadline(), None);
+    }
+
+    #[cfg(all(unix, feature = "mio"))]
+    #[test]
+    fn test_mio_source() {
+        use mio::{Poll, Events, Token, Interest};
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let private_key = crypto::generate_key();
+        let mut server = UdpServer::new("127.0.0.1:0", MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+        let addr = server.get_local_addr().unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.registry().register(&mut server, Token(0), Interest::READABLE).unwrap();
+
+        //Client sends its connection request as soon as it's created
+        let token = ConnectToken::generate([addr].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+        let mut client = UdpClient::new(&token).unwrap();
+        poll.registry().register(&mut client, Token(1), Interest::READABLE).unwrap();
+        assert!(client.as_raw_fd() != server.as_raw_fd());
+
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), vec!(Token(0)));
+
+        //Challenge wakes the client
+        server.update(0.0).unwrap();
+        assert!(server.next_event(&mut scratch).unwrap().is_none());
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), vec!(Token(1)));
+
+        expect_state(&mut client, 0.0, State::SendingConnectionResponse);
+
+        poll.registry().deregister(&mut server).unwrap();
+        poll.registry().deregister(&mut client).unwrap();
+    }
+
+    fn expect_state<T>(client: &mut Client<T>, elapsed: f64, expected: State) where T: Transport {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        client.update(elapsed).unwrap();
+        match client.next_event(&mut scratch) {
+            Ok(Some(ClientEvent::NewState(ref state))) if *state == expected => (),
+            e => assert!(false, "expected {:?} but got {:?}", expected, e)
+        }
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        let private_key = crypto::generate_key();
+        let token = ConnectToken::generate(Vec::<SocketAddr>::new().into_iter(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+
+        let mut client = UdpClient::new(&token).unwrap();
+        expect_state(&mut client, 0.0, State::InvalidConnectToken);
+    }
+
+    #[test]
+    fn test_token_expired() {
+        let private_key = crypto::generate_key();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut token = ConnectToken::generate([socket.local_addr().unwrap()].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+
+        //Token expiring before we connect
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        expect_state(&mut client, 31.0, State::ConnectTokenExpired);
+
+        //Token expired before we started
+        token.create_utc -= 60;
+        token.expire_utc -= 60;
+        let mut client = UdpClient::new(&token).unwrap();
+        expect_state(&mut client, 0.0, State::ConnectTokenExpired);
+
+        //Token created a while ago only has what's left of its lifetime, not another 30s
+        token.create_utc += 60 - 26;
+        token.expire_utc += 60 - 26;
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(client.next_deadline().unwrap() <= 4.0);
+        expect_state(&mut client, 4.5, State::ConnectTokenExpired);
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let private_key = crypto::generate_key();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let token = ConnectToken::generate([socket.local_addr().unwrap()].iter().cloned(), &private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, None).unwrap();
+
+        let mut client = UdpClient::new(&token).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        expect_state(&mut client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::ConnectionRequestTimedOut);
+    }
+
+    #[test]
+    fn test_response_timeout() {
+        let mut harness = TestHarness::<UdpSocket>::new(None);
+
+        harness.update_server();
+        match harness.update_client().unwrap() {
+            ClientEvent::NewState(State::SendingConnectionResponse) => (),
+            s => assert!(false, "{:?}", s)
+        }
+
+        expect_state(&mut harness.client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::ConnectionResponseTimedOut);
+    }
+
+    #[test]
+    fn test_connection_timeout() {
+        let mut harness = TestHarness::<UdpSocket>::new(None);
+
+        harness.update_server();
+        harness.update_client().unwrap();
+
+        harness.update_server();
+        match harness.update_client().unwrap() {
+            ClientEvent::NewState(State::Connected) => (),
+            s => assert!(false, "{:?}", s)
+        }
+
+        expect_state(&mut harness.client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::ConnectionTimedOut);
+    }
+
+    fn connect<T>(harness: &mut TestHarness<T>) where T: BindTransport {
+        harness.update_server();
+        harness.update_client().unwrap();
+
+        harness.update_server();
+        match harness.update_client().unwrap() {
+            ClientEvent::NewState(State::Connected) => (),
+            s => assert!(false, "{:?}", s)
+        }
+    }
+
+    #[test]
+    fn test_client_index() {
+        let mut harness = TestHarness::<UdpSocket>::new(None);
+
+        //Slot 0 is valid so nothing is reported before the server's keep-alive
+        assert_eq!(harness.client.client_index(), None);
+        assert_eq!(harness.client.max_clients(), None);
+
+        connect(&mut harness);
+
+        assert_eq!(harness.client.client_index(), Some(0));
+        assert_eq!(harness.client.max_clients(), Some(MAX_CLIENTS));
+
+        expect_state(&mut harness.client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::ConnectionTimedOut);
+        assert_eq!(harness.client.client_index(), None);
+        assert_eq!(harness.client.max_clients(), None);
+    }
+
+    #[test]
+    fn test_token_timeout() {
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+        //Long timeout outlives the default
+        let mut harness = TestHarness::<UdpSocket>::new_with_timeout(None, 30);
+        connect(&mut harness);
+
+        harness.client.update((NETCODE_TIMEOUT_SECONDS + 1) as f64).unwrap();
+        match harness.client.next_event(&mut scratch) {
+            Ok(Some(ClientEvent::SentKeepAlive)) => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        if let Some(server) = harness.server.as_mut() {
+            server.update((NETCODE_TIMEOUT_SECONDS + 1) as f64).unwrap();
+            loop {
+                match server.next_event(&mut scratch) {
+                    Ok(Some(ServerEvent::ClientDisconnect(_))) => assert!(false),
+                    Ok(None) => break,
+                    _ => ()
+                }
+            }
+        }
+
+        //Zero falls back to the default on both ends
+        let mut harness = TestHarness::<UdpSocket>::new_with_timeout(None, 0);
+        connect(&mut harness);
+
+        if let Some(server) = harness.server.as_mut() {
+            server.update((NETCODE_TIMEOUT_SECONDS + 1) as f64).unwrap();
+            loop {
+                match server.next_event(&mut scratch) {
+                    Ok(Some(ServerEvent::ClientDisconnect(CLIENT_ID))) => break,
+                    Ok(None) => assert!(false),
+                    _ => ()
+                }
+            }
+        }
+
+        harness.client.update((NETCODE_TIMEOUT_SECONDS - 1) as f64).unwrap();
+        match harness.client.next_event(&mut scratch) {
+            Ok(Some(ClientEvent::SentKeepAlive)) => (),
+            e => assert!(false, "{:?}", e)
+        }
+
+        expect_state(&mut harness.client, (NETCODE_TIMEOUT_SECONDS + 1) as f64, State::ConnectionTimedOut);
+
+        //Short timeout expires before the default
+        let mut harness = TestHarness::<UdpSocket>::new_with_timeout(None, 2);
+        connect(&mut harness);
+
+        expect_state(&mut harness.client, 3.0, State::ConnectionTimedOut);
+
+        if let Some(server) = harness.server.as_mut() {
+            server.update(3.0).unwrap();
+            match server.next_event(&mut scratch) {
+                Ok(Some(ServerEvent::ClientDisconnect(CLIENT_ID))) => (),
+                e => assert!(false, "{:?}", e)
+            }
+        }
+    }
+
     #[test]
     fn test_payload() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new(None);
 
         //Pending response
         harness.update_server();
@@ -476,14 +1677,16 @@ mod test {
                 data[d] = d as u8;
             }
 
+            let sequence = harness.client.next_packet_sequence();
             harness.client.send(&data[..i]).unwrap();
             if let Some(server) = harness.server.as_mut() {
                 {
                     server.update(0.0).unwrap();
                     let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
                     match server.next_event(&mut payload) {
-                        Ok(Some(ServerEvent::Packet(client_id, len))) => {
+                        Ok(Some(ServerEvent::Packet(client_id, len, seq))) => {
                             assert_eq!(len, i);
+                            assert_eq!(seq, sequence);
                             assert_eq!(client_id, CLIENT_ID);
                             for d in 0..i {
                                 assert_eq!(payload[d], data[d]);
@@ -495,12 +1698,14 @@ mod test {
                 }
 
                 {
+                    let sequence = server.next_packet_sequence(CLIENT_ID).unwrap();
                     server.send(CLIENT_ID, &data[..i]).unwrap();
                     harness.client.update(0.0).unwrap();
                     let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
                     match harness.client.next_event(&mut payload) {
-                        Ok(Some(ClientEvent::Packet(len))) => {
+                        Ok(Some(ClientEvent::Packet(len, seq))) => {
                             assert_eq!(len, i);
+                            assert_eq!(seq, sequence);
                             for d in 0..i {
                                 assert_eq!(payload[d], data[d]);
                             }
@@ -7,6 +7,8 @@ pub const NETCODE_USER_DATA_BYTES: usize = 256;
 pub const NETCODE_CONNECT_TOKEN_PRIVATE_BYTES: usize = 1024;
 
 pub const NETCODE_TIMEOUT_SECONDS: u32 = 5;
+/// Number of redundant disconnect packets sent when a connection is closed.
+pub const NETCODE_NUM_DISCONNECT_PACKETS: usize = 10;
 
 pub const NETCODE_MAX_SERVERS_PER_CONNECT: usize = 16;
 
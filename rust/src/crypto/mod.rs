@@ -0,0 +1,131 @@
+//! ChaCha20-Poly1305 encryption and random numbers, from libsodium or with the `rust-crypto` feature from pure Rust crates.
+//!
+//! Both backends produce the same bytes, the `rust-crypto` one is used whenever that feature is enabled.
+
+use common::*;
+
+#[cfg(any(all(test, feature = "capi-tests"), not(feature = "rust-crypto")))]
+mod sodium;
+#[cfg(feature = "rust-crypto")]
+mod rust_crypto;
+
+#[cfg(feature = "rust-crypto")]
+use self::rust_crypto as backend;
+#[cfg(not(feature = "rust-crypto"))]
+use self::sodium as backend;
+
+#[cfg(not(any(feature = "sodium", feature = "rust-crypto")))]
+compile_error!("netcode needs a crypto backend, enable either the `sodium` or the `rust-crypto` feature");
+
+pub const NETCODE_ENCRYPT_EXTA_BYTES: usize = 16;
+
+#[derive(Debug)]
+pub enum EncryptError {
+    InvalidPublicKeySize,
+    BufferSizeMismatch,
+    Failed
+}
+
+/// Generates a new random private key.
+pub fn generate_key() -> [u8; NETCODE_KEY_BYTES] {
+    let mut key: [u8; NETCODE_KEY_BYTES] = [0; NETCODE_KEY_BYTES];
+
+    random_bytes(&mut key);
+
+    key
+}
+
+pub fn random_bytes(out: &mut [u8]) {
+    backend::random_bytes(out)
+}
+
+pub fn encode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
+    if key.len() != NETCODE_KEY_BYTES {
+        return Err(EncryptError::InvalidPublicKeySize)
+    }
+
+    if out.len() < data.len() + NETCODE_ENCRYPT_EXTA_BYTES {
+        return Err(EncryptError::BufferSizeMismatch)
+    }
+
+    backend::encode(out, data, additional_data, nonce, key)
+}
+
+pub fn decode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
+    if key.len() != NETCODE_KEY_BYTES {
+        return Err(EncryptError::InvalidPublicKeySize)
+    }
+
+    if data.len() < NETCODE_ENCRYPT_EXTA_BYTES {
+        return Err(EncryptError::Failed)
+    }
+
+    if out.len() < data.len() - NETCODE_ENCRYPT_EXTA_BYTES {
+        return Err(EncryptError::BufferSizeMismatch)
+    }
+
+    backend::decode(out, data, additional_data, nonce, key)
+}
+
+#[test]
+fn test_known_answer() {
+    //Test vector from draft-agl-tls-chacha20poly1305, also used by libsodium's own tests
+    let key = [
+        0x42, 0x90, 0xbc, 0xb1, 0x54, 0x17, 0x35, 0x31, 0xf3, 0x14, 0xaf, 0x57, 0xf3, 0xbe, 0x3b, 0x50,
+        0x06, 0xda, 0x37, 0x1e, 0xce, 0x27, 0x2a, 0xfa, 0x1b, 0x5d, 0xbd, 0xd1, 0x10, 0x0a, 0x10, 0x07];
+    let nonce = 0x4a799ce37bf67ccd;
+    let data = [0x86, 0xd0, 0x99, 0x74, 0x84, 0x0b, 0xde, 0xd2, 0xa5, 0xca];
+    let additional_data = [0x87, 0xe2, 0x29, 0xd4, 0x50, 0x08, 0x45, 0xa0, 0x79, 0xc0];
+    let expected = [
+        0xe3, 0xe4, 0x46, 0xf7, 0xed, 0xe9, 0xa1, 0x9b, 0x62, 0xa4, 0x67, 0x7d, 0xab, 0xf4, 0xe3, 0xd2,
+        0x4b, 0x87, 0x6b, 0xb2, 0x84, 0x75, 0x38, 0x96, 0xe1, 0xd6];
+
+    let mut encoded = [0; 64];
+    let len = encode(&mut encoded, &data, Some(&additional_data), nonce, &key).unwrap();
+    assert_eq!(&encoded[..len], &expected[..]);
+
+    let mut decoded = [0; 64];
+    let len = decode(&mut decoded, &expected, Some(&additional_data), nonce, &key).unwrap();
+    assert_eq!(&decoded[..len], &data[..]);
+
+    let mut tampered = expected;
+    tampered[0] ^= 1;
+    assert!(decode(&mut decoded, &tampered, Some(&additional_data), nonce, &key).is_err());
+    assert!(decode(&mut decoded, &expected, None, nonce, &key).is_err());
+    assert!(decode(&mut decoded, &expected[..NETCODE_ENCRYPT_EXTA_BYTES - 1], None, nonce, &key).is_err());
+}
+
+#[cfg(all(feature = "rust-crypto", feature = "capi-tests"))]
+#[test]
+fn test_backend_interop() {
+    let key = generate_key();
+    let additional_data = [0xAB; 13];
+
+    for &len in [0, 1, 15, 16, 63, 64, 65, 300, NETCODE_MAX_PACKET_SIZE].iter() {
+        for &ad in [None, Some(&additional_data[..])].iter() {
+            let nonce = len as u64 * 0x0101010101;
+            let mut data = vec!(0; len);
+            sodium::random_bytes(&mut data);
+
+            let mut sodium_encoded = vec!(0; len + NETCODE_ENCRYPT_EXTA_BYTES);
+            let mut rust_encoded = vec!(0; len + NETCODE_ENCRYPT_EXTA_BYTES);
+            sodium::encode(&mut sodium_encoded, &data, ad, nonce, &key).unwrap();
+            rust_crypto::encode(&mut rust_encoded, &data, ad, nonce, &key).unwrap();
+            assert_eq!(sodium_encoded, rust_encoded);
+
+            let mut decoded = vec!(0; len);
+            assert_eq!(rust_crypto::decode(&mut decoded, &sodium_encoded, ad, nonce, &key).unwrap(), len);
+            assert_eq!(decoded, data);
+
+            let mut decoded = vec!(0; len);
+            assert_eq!(sodium::decode(&mut decoded, &rust_encoded, ad, nonce, &key).unwrap(), len);
+            assert_eq!(decoded, data);
+
+            //Both reject the same forgery
+            rust_encoded[len / 2] ^= 0x80;
+            assert!(sodium::decode(&mut decoded, &rust_encoded, ad, nonce, &key).is_err());
+            assert!(rust_crypto::decode(&mut decoded, &rust_encoded, ad, nonce + 1, &key).is_err());
+            assert!(rust_crypto::decode(&mut decoded, &rust_encoded, ad, nonce, &key).is_err());
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Pure Rust backend, the original (non-IETF) ChaCha20-Poly1305 construction libsodium's `crypto_aead_chacha20poly1305` uses.
+//!
+//! The nonce is 64 bits, the first keystream block keys Poly1305 and the tag covers the additional data and ciphertext
+//! each followed by their little endian length, without the padding the IETF version adds.
+
+use chacha20::{self, ChaCha20Legacy};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use poly1305::{self, Poly1305};
+use poly1305::universal_hash::{KeyInit, UniversalHash};
+use getrandom;
+use byteorder::{LittleEndian, ByteOrder};
+
+use std::cmp;
+use std::slice;
+
+use common::*;
+use super::{EncryptError, NETCODE_ENCRYPT_EXTA_BYTES};
+
+pub fn random_bytes(out: &mut [u8]) {
+    getrandom::getrandom(out).expect("Unable to read random bytes from the OS");
+}
+
+pub fn encode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
+    let (mut cipher, mac) = init(nonce, key);
+
+    out[..data.len()].copy_from_slice(data);
+    cipher.apply_keystream(&mut out[..data.len()]);
+
+    let tag = compute_tag(mac, additional_data.unwrap_or(&[]), &out[..data.len()]);
+    out[data.len()..data.len() + NETCODE_ENCRYPT_EXTA_BYTES].copy_from_slice(&tag);
+
+    Ok(data.len() + NETCODE_ENCRYPT_EXTA_BYTES)
+}
+
+pub fn decode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
+    let (mut cipher, mac) = init(nonce, key);
+
+    let (ciphertext, tag) = data.split_at(data.len() - NETCODE_ENCRYPT_EXTA_BYTES);
+    let expected = compute_tag(mac, additional_data.unwrap_or(&[]), ciphertext);
+
+    //Compare every byte so a forged tag can't be found one byte at a time
+    if expected.iter().zip(tag.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return Err(EncryptError::Failed)
+    }
+
+    out[..ciphertext.len()].copy_from_slice(ciphertext);
+    cipher.apply_keystream(&mut out[..ciphertext.len()]);
+
+    Ok(ciphertext.len())
+}
+
+fn init(nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> (ChaCha20Legacy, Poly1305) {
+    let mut nonce_bytes = [0; 8];
+    LittleEndian::write_u64(&mut nonce_bytes, nonce);
+
+    let mut cipher = ChaCha20Legacy::new(chacha20::Key::from_slice(key), chacha20::LegacyNonce::from_slice(&nonce_bytes));
+
+    //Block 0 keys the MAC, data is encrypted starting at block 1
+    let mut block = [0; 64];
+    cipher.apply_keystream(&mut block);
+    let mac = Poly1305::new(poly1305::Key::from_slice(&block[..poly1305::KEY_SIZE]));
+
+    (cipher, mac)
+}
+
+fn compute_tag(mac: Poly1305, additional_data: &[u8], ciphertext: &[u8]) -> poly1305::Tag {
+    let mut input = MacInput::new(mac);
+    let mut len = [0; 8];
+
+    input.update(additional_data);
+    LittleEndian::write_u64(&mut len, additional_data.len() as u64);
+    input.update(&len);
+
+    input.update(ciphertext);
+    LittleEndian::write_u64(&mut len, ciphertext.len() as u64);
+    input.update(&len);
+
+    input.finalize()
+}
+
+/// Feeds Poly1305 back to back inputs without padding between them, holding on to a partial block until the next input fills it.
+struct MacInput {
+    mac: Poly1305,
+    partial: [u8; poly1305::BLOCK_SIZE],
+    partial_len: usize
+}
+
+impl MacInput {
+    fn new(mac: Poly1305) -> MacInput {
+        MacInput {
+            mac: mac,
+            partial: [0; poly1305::BLOCK_SIZE],
+            partial_len: 0
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        if self.partial_len > 0 {
+            let take = cmp::min(poly1305::BLOCK_SIZE - self.partial_len, data.len());
+            self.partial[self.partial_len..self.partial_len + take].copy_from_slice(&data[..take]);
+            self.partial_len += take;
+            data = &data[take..];
+
+            if self.partial_len < poly1305::BLOCK_SIZE {
+                return
+            }
+
+            self.mac.update(slice::from_ref(poly1305::Block::from_slice(&self.partial)));
+            self.partial_len = 0;
+        }
+
+        let full = data.len() - data.len() % poly1305::BLOCK_SIZE;
+        for block in data[..full].chunks(poly1305::BLOCK_SIZE) {
+            self.mac.update(slice::from_ref(poly1305::Block::from_slice(block)));
+        }
+
+        let rest = &data[full..];
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+    }
+
+    /// Last partial block gets Poly1305's own padding rather than zeros.
+    fn finalize(self) -> poly1305::Tag {
+        self.mac.compute_unpadded(&self.partial[..self.partial_len])
+    }
+}
@@ -1,24 +1,9 @@
+//! libsodium backend, `crypto_aead_chacha20poly1305` through `libsodium-sys`.
+
 use libsodium_sys;
 
 use common::*;
-
-pub const NETCODE_ENCRYPT_EXTA_BYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_ABYTES;
-
-#[derive(Debug)]
-pub enum EncryptError {
-    InvalidPublicKeySize,
-    BufferSizeMismatch,
-    Failed
-}
-
-/// Generates a new random private key.
-pub fn generate_key() -> [u8; NETCODE_KEY_BYTES] {
-    let mut key: [u8; NETCODE_KEY_BYTES] = [0; NETCODE_KEY_BYTES];
-
-    random_bytes(&mut key);
-
-    key
-}
+use super::EncryptError;
 
 pub fn random_bytes(out: &mut [u8]) {
     unsafe {
@@ -27,14 +12,6 @@ pub fn random_bytes(out: &mut [u8]) {
 }
 
 pub fn encode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
-    if key.len() != NETCODE_KEY_BYTES {
-        return Err(EncryptError::InvalidPublicKeySize)
-    }
-
-    if out.len() < data.len() + NETCODE_ENCRYPT_EXTA_BYTES {
-        return Err(EncryptError::BufferSizeMismatch)
-    }
-
     let (result, written) = unsafe {
         let mut written: u64 = out.len() as u64;
 
@@ -59,14 +36,6 @@ pub fn encode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce
 }
 
 pub fn decode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce: u64, key: &[u8; NETCODE_KEY_BYTES]) -> Result<usize, EncryptError> {
-    if key.len() != NETCODE_KEY_BYTES {
-        return Err(EncryptError::InvalidPublicKeySize)
-    }
-
-    if out.len() < data.len() - NETCODE_ENCRYPT_EXTA_BYTES {
-        return Err(EncryptError::BufferSizeMismatch)
-    }
-
     let (result, read) = unsafe {
         let mut read: u64 = out.len() as u64;
 
@@ -88,4 +57,4 @@ pub fn decode(out: &mut [u8], data: &[u8], additional_data: Option<&[u8]>, nonce
         -1 => Err(EncryptError::Failed),
         _ => Ok(read as usize)
     }
-}
\ No newline at end of file
+}
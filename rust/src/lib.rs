@@ -11,6 +11,18 @@
 //! The netcode.io server is created within the [UDPServer](type.UdpServer.html)::new(...) call. It accepts a local address, number of clients and private key
 //! used to sign the `ConnectToken`s send to the connecting clients.
 //!
+//! # Transport
+//! `UdpServer` and `UdpClient` send packets over `std::net::UdpSocket`. Implement [Transport](trait.Transport.html) and construct with
+//! `Server::with_transport(..)` or `Client::with_transport(..)` to run netcode.io over relays, tunnels or an in-process transport.
+//!
+//! # Async
+//! With the `async-tokio` feature `AsyncServer` and `AsyncClient` wrap a `TokioServer`/`TokioClient` as a `Stream` of events,
+//! waking when packets arrive and on a timer that drives keep-alives and timeouts.
+//!
+//! # Crypto
+//! Packets are encrypted with libsodium by default. Building with `--no-default-features --features rust-crypto` swaps in pure Rust
+//! ChaCha20-Poly1305 and the OS random number generator so libsodium isn't needed, both produce the same bytes on the wire.
+//!
 //! # Example
 //! ```
 //! use netcode::UdpServer;
@@ -29,7 +41,7 @@
 //!             match e {
 //!                 ServerEvent::ClientConnect(_id) => {},
 //!                 ServerEvent::ClientDisconnect(_id) => {},
-//!                 ServerEvent::Packet(_id,_size) => {},
+//!                 ServerEvent::Packet(_id,_size,_seq) => {},
 //!                 _ => ()
 //!             }
 //!         },
@@ -39,10 +51,25 @@
 //! //}
 //! ```
 
+#[cfg(any(feature = "sodium", feature = "capi-tests"))]
 extern crate libsodium_sys;
+#[cfg(feature = "rust-crypto")]
+extern crate chacha20;
+#[cfg(feature = "rust-crypto")]
+extern crate poly1305;
+#[cfg(feature = "rust-crypto")]
+extern crate getrandom;
 extern crate byteorder;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "async-tokio")]
+extern crate tokio;
+#[cfg(feature = "async-tokio")]
+extern crate futures_core;
+#[cfg(all(unix, feature = "mio"))]
+extern crate mio;
+#[cfg(all(target_os = "linux", feature = "batch-io"))]
+extern crate libc;
 
 #[cfg(test)]
 extern crate env_logger;
@@ -50,7 +77,7 @@ extern crate env_logger;
 #[macro_use]
 extern crate lazy_static;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "capi-tests"))]
 pub mod capi;
 
 mod common;
@@ -63,10 +90,23 @@ mod replay;
 mod token;
 mod packet;
 mod socket;
+mod simulator;
+mod loopback;
+#[cfg(feature = "async-tokio")]
+mod async_tokio;
+#[cfg(all(target_os = "linux", feature = "batch-io"))]
+mod batch;
 
 pub use token::{ConnectToken};
 pub use common::{NETCODE_MAX_PACKET_SIZE, NETCODE_MAX_PAYLOAD_SIZE, NETCODE_USER_DATA_BYTES};
-pub use server::{UdpServer, Server, ServerEvent};
-pub use client::{UdpClient, Client, ClientEvent};
+pub use server::{UdpServer, Server, ServerEvent, ServerSender, LoopbackError};
+pub use client::{UdpClient, Client, ClientEvent, State};
 pub use crypto::{generate_key};
+pub use socket::{Transport, BindTransport, CloneTransport};
+pub use simulator::{Simulator, SimulatorConfig, SimulatedTransport};
+pub use loopback::LoopbackEndpoint;
+#[cfg(feature = "async-tokio")]
+pub use async_tokio::{TokioUdpSocket, TokioServer, TokioClient, AsyncServer, AsyncClient};
+#[cfg(all(target_os = "linux", feature = "batch-io"))]
+pub use batch::{BatchUdpSocket, BatchUdpServer};
 pub use error::*;
\ No newline at end of file
@@ -0,0 +1,157 @@
+//! In-memory queues connecting a loopback client to its server slot, used for listen-servers where the host plays locally.
+
+use common::*;
+use error::SendError;
+
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+
+struct PacketQueue {
+    packets: VecDeque<(Vec<u8>, u64)>,
+    next_sequence: u64
+}
+
+impl PacketQueue {
+    fn new() -> PacketQueue {
+        PacketQueue {
+            packets: VecDeque::new(),
+            next_sequence: 0
+        }
+    }
+}
+
+struct LoopbackState {
+    to_server: PacketQueue,
+    to_client: PacketQueue,
+    disconnected: bool
+}
+
+/// One end of a loopback slot. `Server::connect_loopback_client(..)` returns the client's end which is passed to `Client::new_loopback(..)`.
+///
+/// Payloads are moved between ends without encryption or sockets, either side disconnecting is seen by the other on its next `next_event(..)`.
+pub struct LoopbackEndpoint {
+    state: Arc<Mutex<LoopbackState>>,
+    is_server: bool,
+    client_idx: usize,
+    max_clients: usize
+}
+
+impl Clone for LoopbackEndpoint {
+    fn clone(&self) -> LoopbackEndpoint {
+        LoopbackEndpoint {
+            state: self.state.clone(),
+            is_server: self.is_server,
+            client_idx: self.client_idx,
+            max_clients: self.max_clients
+        }
+    }
+}
+
+impl LoopbackEndpoint {
+    /// Creates a connected pair of endpoints, (server, client).
+    pub(crate) fn pair(client_idx: usize, max_clients: usize) -> (LoopbackEndpoint, LoopbackEndpoint) {
+        let state = Arc::new(Mutex::new(LoopbackState {
+            to_server: PacketQueue::new(),
+            to_client: PacketQueue::new(),
+            disconnected: false
+        }));
+
+        let server = LoopbackEndpoint {
+            state: state.clone(),
+            is_server: true,
+            client_idx: client_idx,
+            max_clients: max_clients
+        };
+
+        let client = LoopbackEndpoint {
+            state: state,
+            is_server: false,
+            client_idx: client_idx,
+            max_clients: max_clients
+        };
+
+        (server, client)
+    }
+
+    /// Queues `payload` for the other end, returns the sequence number it was sent with.
+    pub(crate) fn send(&self, payload: &[u8]) -> Result<u64, SendError> {
+        //Checked here as well as by callers since `recv` copies into a fixed size buffer
+        if payload.len() == 0 || payload.len() > NETCODE_MAX_PAYLOAD_SIZE {
+            return Err(SendError::PacketSize)
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected {
+            return Err(SendError::Disconnected)
+        }
+
+        let queue = if self.is_server { &mut state.to_client } else { &mut state.to_server };
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.packets.push_back((payload.to_vec(), sequence));
+
+        Ok(sequence)
+    }
+
+    /// Takes the next payload sent from the other end, returns its length and sequence number.
+    pub(crate) fn recv(&self, out_payload: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Option<(usize, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let queue = if self.is_server { &mut state.to_server } else { &mut state.to_client };
+
+        queue.packets.pop_front().map(|(payload, sequence)| {
+            out_payload[..payload.len()].copy_from_slice(&payload);
+            (payload.len(), sequence)
+        })
+    }
+
+    pub(crate) fn get_next_sequence(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        if self.is_server { state.to_client.next_sequence } else { state.to_server.next_sequence }
+    }
+
+    /// Disconnects both ends, payloads already queued can still be received.
+    pub(crate) fn disconnect(&self) {
+        self.state.lock().unwrap().disconnected = true;
+    }
+
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.state.lock().unwrap().disconnected
+    }
+
+    pub(crate) fn client_index(&self) -> usize {
+        self.client_idx
+    }
+
+    pub(crate) fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+}
+
+#[test]
+fn test_loopback_queues() {
+    let (server, client) = LoopbackEndpoint::pair(3, 8);
+    assert_eq!(client.client_index(), 3);
+    assert_eq!(client.max_clients(), 8);
+
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+    assert_eq!(server.recv(&mut payload), None);
+
+    assert_eq!(client.send(&[1, 2, 3]).unwrap(), 0);
+    assert_eq!(client.send(&[4]).unwrap(), 1);
+    assert!(client.send(&[0; NETCODE_MAX_PAYLOAD_SIZE + 1]).is_err());
+    assert_eq!(client.get_next_sequence(), 2);
+    assert_eq!(server.get_next_sequence(), 0);
+
+    assert_eq!(server.recv(&mut payload), Some((3, 0)));
+    assert_eq!(&payload[..3], &[1, 2, 3]);
+    assert_eq!(server.recv(&mut payload), Some((1, 1)));
+    assert_eq!(payload[0], 4);
+    assert_eq!(client.recv(&mut payload), None);
+
+    server.send(&[5]).unwrap();
+    server.disconnect();
+    assert!(client.is_disconnected());
+    assert!(client.send(&[6]).is_err());
+    assert_eq!(client.recv(&mut payload), Some((1, 0)));
+    assert_eq!(server.recv(&mut payload), None);
+}
@@ -50,6 +50,11 @@ impl Packet {
     }
 }
 
+/// Connection requests are the only packets sent in the clear, this lets callers check before picking a key.
+pub fn is_connection_request(data: &[u8]) -> bool {
+    data.len() > 0 && decode_prefix(data[0]).0 == PACKET_CONNECTION
+}
+
 fn decode_prefix(value: u8) -> (u8, usize) {
     ((value & 0xF) as u8, (value >> 4) as usize)
 }
@@ -212,6 +217,11 @@ pub fn encode(out: &mut [u8], protocol_id: u64, packet: &Packet, crypt_info: Opt
     }
 }
 
+/// Size of an encoded payload packet, lets us report it before the packet has been encrypted.
+pub fn payload_packet_len(sequence: u64, payload_len: usize) -> usize {
+    1 + sequence_bytes_required(sequence) + payload_len + crypto::NETCODE_ENCRYPT_EXTA_BYTES
+}
+
 pub struct ConnectionRequestPacket {
     pub version: [u8; NETCODE_VERSION_LEN],
     pub protocol_id: u64,
@@ -450,6 +460,7 @@ fn test_sequence() {
 }
 
 #[cfg(test)]
+#[cfg_attr(not(feature = "capi-tests"), allow(unused_mut, unused_variables))]
 fn test_encode_decode<V>(
         packet: Packet,
         payload: Option<&[u8]>,
@@ -483,6 +494,8 @@ fn test_encode_decode<V>(
         }
     }
 
+    //Check the C library reads it too
+    #[cfg(feature = "capi-tests")]
     unsafe {
         #[allow(unused_variables)]
         let lock = ::common::test::FFI_LOCK.lock().unwrap();
@@ -683,6 +696,19 @@ fn test_payload_packet() {
 }
 
 #[test]
+fn test_payload_packet_len() {
+    let key = crypto::generate_key();
+    let data = [0xAA; 64];
+    let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+
+    for &sequence in [0, 0xFF, 0xCCDD, 0xFFFFFFFFFF].iter() {
+        let len = encode(&mut scratch, 0xFFCC, &Packet::Payload(data.len()), Some((sequence, &key)), Some(&data)).unwrap();
+        assert_eq!(len, payload_packet_len(sequence, data.len()));
+    }
+}
+
+#[test]
+#[cfg_attr(not(feature = "capi-tests"), allow(unused_mut))]
 fn test_decode_challenge_token() {
     let mut user_data = [0; NETCODE_USER_DATA_BYTES];
     for i in 0..user_data.len() {
@@ -704,7 +730,8 @@ fn test_decode_challenge_token() {
         assert_eq!(user_data[i], decoded.user_data[i]);
     }
 
-   unsafe {
+    #[cfg(feature = "capi-tests")]
+    unsafe {
         #[allow(unused_variables)]
         let lock = ::common::test::FFI_LOCK.lock().unwrap();
 
@@ -1,11 +1,11 @@
 use server;
 use channel::Channel;
+use common::*;
+use loopback::LoopbackEndpoint;
 
 /// Current state of the client connection.
 #[derive(Clone, Debug)]
 pub enum ConnectionState {
-    /// We've recieved the initial packet but response is outstanding yet.
-    PendingResponse,
     /// Connection is idle and waiting to send heartbeat.
     Idle,
     /// Client timed out from heartbeat packets.
@@ -15,9 +15,25 @@ pub enum ConnectionState {
 }
 
 /// Handle to client connection.
-#[derive(Clone)]
 pub struct Connection {
     pub client_id: server::ClientId,
     pub state: ConnectionState,
+    /// Index of the listen socket this client connected on, replies go out the same socket.
+    pub socket_idx: usize,
+    pub channel: Channel,
+    /// User data from the client's connect token.
+    pub user_data: [u8; NETCODE_USER_DATA_BYTES],
+    /// Set once we've heard from the client after connecting, until then payloads are preceded by a keep alive.
+    pub confirmed: bool,
+    /// Set for loopback clients, payloads go through these queues instead of `channel`.
+    pub loopback: Option<LoopbackEndpoint>
+}
+
+/// Client that has been sent a challenge but hasn't responded yet, these don't hold a client slot.
+pub struct PendingConnection {
+    pub client_id: server::ClientId,
+    /// MAC of the connect token used so a retried request can reuse this handshake.
+    pub token_mac: [u8; NETCODE_MAC_BYTES],
+    pub socket_idx: usize,
     pub channel: Channel
 }
@@ -1,9 +1,19 @@
 //! This module holds a netcode.io server implemenation and all of its related functions.
 
-use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
+use std::net::{ToSocketAddrs, SocketAddr, UdpSocket, IpAddr, Ipv4Addr};
 use std::io;
+use std::collections::{VecDeque, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 #[cfg(test)]
 use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(all(unix, feature = "mio"))]
+use mio;
+#[cfg(all(unix, feature = "mio"))]
+use mio::unix::SourceFd;
 
 use common::*;
 use packet;
@@ -11,10 +21,23 @@ use token;
 use crypto;
 
 mod connection;
+mod token_history;
+mod workers;
+mod sender;
 use server::connection::*;
+use server::token_history::TokenHistory;
+use server::workers::{WorkerPool, DecodeJob, EncodeJob, Decoded};
+use server::sender::{SendSlot, SendSlots, SendClock};
+pub use server::sender::ServerSender;
 use socket::*;
 use error::*;
 use channel::{self, Channel};
+use loopback::LoopbackEndpoint;
+
+/// Number of in-flight handshakes allowed per client slot.
+const PENDING_CONNECTIONS_PER_CLIENT: usize = 4;
+/// Most packets handed to worker threads before we stop reading and wait for them.
+const MAX_DECODES_IN_FLIGHT: usize = 256;
 
 /// Errors from creating a server.
 #[derive(Debug)]
@@ -33,6 +56,17 @@ impl From<io::Error> for CreateError {
     }
 }
 
+/// Errors from connecting a loopback client.
+#[derive(Debug)]
+pub enum LoopbackError {
+    /// Client index is outside of `max_clients`.
+    InvalidClientIndex,
+    /// Client slot is already taken by another client.
+    SlotInUse,
+    /// A client with the same id is already connected.
+    ClientIdInUse
+}
+
 pub type ClientId = u64;
 
 /// Describes event the server receives when calling `next_event(..)`.
@@ -44,8 +78,8 @@ pub enum ServerEvent {
     ClientDisconnect(ClientId),
     /// Called when client tries to connect but all slots are full.
     ClientSlotFull,
-    /// We received a packet, `out_packet` will be filled with data based on `usize`, contains the client id that reieved the packet and length of the packet.
-    Packet(ClientId, usize),
+    /// We received a packet, `out_packet` will be filled with data based on `usize`, contains the client id that reieved the packet, length of the packet and its sequence number.
+    Packet(ClientId, usize, u64),
     /// We received a keep alive packet with included client id.
     KeepAlive(ClientId),
     /// Client failed connection token validation
@@ -55,7 +89,7 @@ pub enum ServerEvent {
 }
 
 /// UDP based netcode server.
-pub type UdpServer = Server<UdpSocket,()>;
+pub type UdpServer = Server<UdpSocket>;
 
 /// Netcode server object.
 /// # Example
@@ -76,7 +110,7 @@ pub type UdpServer = Server<UdpSocket,()>;
 ///             match e {
 ///                 ServerEvent::ClientConnect(_id) => {},
 ///                 ServerEvent::ClientDisconnect(_id) => {},
-///                 ServerEvent::Packet(_id,_size) => {},
+///                 ServerEvent::Packet(_id,_size,_seq) => {},
 ///                 _ => ()
 ///             }
 ///         },
@@ -85,22 +119,37 @@ pub type UdpServer = Server<UdpSocket,()>;
 ///     }
 /// //}
 /// ```
-pub struct Server<I,S> {
-    #[allow(dead_code)]
-    socket_state: S,
-    listen_socket: I,
-    listen_addr: SocketAddr,
+pub struct Server<T> where T: Transport {
+    /// Sockets we accept clients on, more than one when running dual-stack.
+    listen_sockets: Vec<T>,
+    /// Addresses clients use to reach us, connect tokens must contain one of these.
+    public_addrs: Vec<SocketAddr>,
     protocol_id: u64,
     connect_key: [u8; NETCODE_KEY_BYTES],
-    //@todo: We could probably use a free list or something smarter here if
-    //we find that performance is an issue.
     clients: Vec<Option<Connection>>,
+    /// Slot of each connected client by address, loopback clients aren't included since they have no address.
+    client_by_addr: HashMap<SocketAddr, usize>,
+    /// Slot of each connected client by client id.
+    client_by_id: HashMap<ClientId, usize>,
+    /// Unused slots, popped from the back. Starts out lowest index first, after that the most recently freed slot is reused first.
+    free_slots: Vec<usize>,
+    pending_connections: Vec<Option<PendingConnection>>,
     time: f64,
 
     challenge_sequence: u64,
     challenge_key: [u8; NETCODE_KEY_BYTES],
+    token_history: TokenHistory,
 
     client_event_idx: usize,
+    pending_events: VecDeque<ServerEvent>,
+    /// Number of connected loopback clients, lets us skip polling their queues when there are none.
+    loopback_clients: usize,
+    /// Set by `set_worker_threads(..)`, encryption and decryption for connected clients happens on these threads.
+    workers: Option<WorkerPool>,
+    /// Connected clients shared with `ServerSender`s, only kept once `sender()` has been called.
+    send_slots: Option<SendSlots>,
+    /// Our time shared with `ServerSender`s, set along with `send_slots`.
+    send_clock: Option<SendClock>
 }
 
 enum TickResult {
@@ -109,51 +158,157 @@ enum TickResult {
     SendKeepAlive
 }
 
-impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
+impl<T> Server<T> where T: BindTransport {
     /// Constructs a new Server bound to `local_addr` with `max_clients` and supplied `private_key` for authentication.
+    /// Connect tokens are validated against the address the server ends up bound to.
     pub fn new<A>(local_addr: A, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES]) 
-            -> Result<Server<I,S>, CreateError>
+            -> Result<Server<T>, CreateError>
             where A: ToSocketAddrs {
-        let bind_addr = local_addr.to_socket_addrs().unwrap().next().unwrap();
-        let mut socket_state = I::new_state();
-        match I::bind(&bind_addr, &mut socket_state) {
-            Ok(s) => {
-                let mut key_copy: [u8; NETCODE_KEY_BYTES] = [0; NETCODE_KEY_BYTES];
-                key_copy.copy_from_slice(private_key);
-
-                let mut clients = Vec::with_capacity(max_clients);
-                for _ in 0..max_clients {
-                    clients.push(None);
-                }
+        let bind_addr = Self::resolve_bind_addr(local_addr)?;
+        Self::new_internal(Self::bind_all(&[bind_addr])?, None, max_clients, protocol_id, private_key)
+    }
 
-                trace!("Started server on {:?}", s.local_addr().unwrap());
-
-                Ok(Server {
-                    socket_state: socket_state,
-                    listen_socket: s,
-                    listen_addr: bind_addr,
-                    protocol_id: protocol_id,
-                    connect_key: key_copy,
-                    clients: clients,
-                    time: 0.0,
-                    challenge_sequence: 0,
-                    challenge_key: crypto::generate_key(),
-                    client_event_idx: 0,
-                })
-            },
-            Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::AddrInUse => Err(CreateError::AddrInUse),
-                    io::ErrorKind::AddrNotAvailable => Err(CreateError::AddrNotAvailable),
-                    _ => Err(CreateError::GenericIo(e))
+    /// Constructs a new Server bound to `local_addr` that validates connect tokens against `public_addrs` instead.
+    /// Use this when clients reach the server through a different address, such as behind NAT or a load balancer.
+    pub fn new_with_public_addrs<A,P>(local_addr: A, public_addrs: P, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError>
+            where A: ToSocketAddrs, P: ToSocketAddrs {
+        let public_addrs = Self::resolve_public_addrs(public_addrs)?;
+        let bind_addr = Self::resolve_bind_addr(local_addr)?;
+        Self::new_internal(Self::bind_all(&[bind_addr])?, Some(public_addrs), max_clients, protocol_id, private_key)
+    }
+
+    /// Constructs a new Server listening on both `ipv4_addr` and `ipv6_addr`, replies are sent from the socket the client connected on.
+    /// Connect tokens are validated against both bound addresses, so this only works when binding specific addresses. Use
+    /// `new_dual_stack_with_public_addrs(..)` when binding the wildcard addresses since tokens can't list `0.0.0.0` or `[::]`.
+    ///
+    /// Some platforms(including Linux) make IPv6 sockets dual-stack by default, so binding `0.0.0.0` and `[::]` to the same port will fail
+    /// with `CreateError::AddrInUse`. Either use different ports, or bind a single `[::]` socket with `new_with_public_addrs(..)` to
+    /// accept v4-mapped addresses.
+    pub fn new_dual_stack<A,B>(ipv4_addr: A, ipv6_addr: B, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError>
+            where A: ToSocketAddrs, B: ToSocketAddrs {
+        Self::new_dual_stack_internal(ipv4_addr, ipv6_addr, None, max_clients, protocol_id, private_key)
+    }
+
+    /// Constructs a new Server listening on both `ipv4_addr` and `ipv6_addr` that validates connect tokens against `public_addrs`,
+    /// see `new_dual_stack(..)` and `new_with_public_addrs(..)`.
+    pub fn new_dual_stack_with_public_addrs<A,B,P>(ipv4_addr: A, ipv6_addr: B, public_addrs: P, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError>
+            where A: ToSocketAddrs, B: ToSocketAddrs, P: ToSocketAddrs {
+        let public_addrs = Self::resolve_public_addrs(public_addrs)?;
+        Self::new_dual_stack_internal(ipv4_addr, ipv6_addr, Some(public_addrs), max_clients, protocol_id, private_key)
+    }
+
+    fn new_dual_stack_internal<A,B>(ipv4_addr: A, ipv6_addr: B, public_addrs: Option<Vec<SocketAddr>>, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError>
+            where A: ToSocketAddrs, B: ToSocketAddrs {
+        let ipv4_addr = ipv4_addr.to_socket_addrs()?.find(|addr| addr.is_ipv4());
+        let ipv6_addr = ipv6_addr.to_socket_addrs()?.find(|addr| addr.is_ipv6());
+
+        match (ipv4_addr, ipv6_addr) {
+            (Some(ipv4_addr), Some(ipv6_addr)) => Self::new_internal(Self::bind_all(&[ipv4_addr, ipv6_addr])?, public_addrs, max_clients, protocol_id, private_key),
+            _ => Err(CreateError::GenericIo(io::Error::new(io::ErrorKind::InvalidInput, "expected one IPv4 and one IPv6 address")))
+        }
+    }
+
+    fn resolve_bind_addr<A>(local_addr: A) -> Result<SocketAddr, CreateError> where A: ToSocketAddrs {
+        match local_addr.to_socket_addrs()?.next() {
+            Some(addr) => Ok(addr),
+            None => Err(CreateError::GenericIo(io::Error::new(io::ErrorKind::InvalidInput, "no local address supplied")))
+        }
+    }
+
+    fn resolve_public_addrs<P>(public_addrs: P) -> Result<Vec<SocketAddr>, CreateError> where P: ToSocketAddrs {
+        let public_addrs = public_addrs.to_socket_addrs()?.collect::<Vec<_>>();
+        if public_addrs.len() == 0 {
+            return Err(CreateError::GenericIo(io::Error::new(io::ErrorKind::InvalidInput, "no public addresses supplied")))
+        }
+
+        Ok(public_addrs)
+    }
+
+    fn bind_all(bind_addrs: &[SocketAddr]) -> Result<Vec<T>, CreateError> {
+        let mut listen_sockets = Vec::with_capacity(bind_addrs.len());
+        for bind_addr in bind_addrs.iter() {
+            match T::bind(bind_addr) {
+                Ok(s) => {
+                    trace!("Started server on {:?}", s.local_addr().unwrap());
+                    listen_sockets.push(s);
+                },
+                Err(e) => {
+                    return match e.kind() {
+                        io::ErrorKind::AddrInUse => Err(CreateError::AddrInUse),
+                        io::ErrorKind::AddrNotAvailable => Err(CreateError::AddrNotAvailable),
+                        _ => Err(CreateError::GenericIo(e))
+                    }
                 }
             }
         }
+
+        Ok(listen_sockets)
     }
+}
+
+impl<T> Server<T> where T: Transport {
+    /// Constructs a new Server listening on a custom `transport`, see [Transport](trait.Transport.html).
+    /// Connect tokens are validated against the transport's `local_addr()`.
+    pub fn with_transport(transport: T, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError> {
+        Self::new_internal(vec!(transport), None, max_clients, protocol_id, private_key)
+    }
+
+    fn new_internal(listen_sockets: Vec<T>, public_addrs: Option<Vec<SocketAddr>>, max_clients: usize, protocol_id: u64, private_key: &[u8; NETCODE_KEY_BYTES])
+            -> Result<Server<T>, CreateError> {
+        let mut key_copy: [u8; NETCODE_KEY_BYTES] = [0; NETCODE_KEY_BYTES];
+        key_copy.copy_from_slice(private_key);
+
+        let mut clients = Vec::with_capacity(max_clients);
+        for _ in 0..max_clients {
+            clients.push(None);
+        }
+
+        let mut pending_connections = Vec::with_capacity(max_clients * PENDING_CONNECTIONS_PER_CLIENT);
+        for _ in 0..max_clients * PENDING_CONNECTIONS_PER_CLIENT {
+            pending_connections.push(None);
+        }
 
-    /// Gets the local port that this server is bound to.
+        let public_addrs = match public_addrs {
+            Some(addrs) => addrs,
+            None => listen_sockets.iter().map(|s| s.local_addr()).collect::<Result<Vec<_>, _>>()?
+        };
+
+        Ok(Server {
+            listen_sockets: listen_sockets,
+            public_addrs: public_addrs,
+            protocol_id: protocol_id,
+            connect_key: key_copy,
+            clients: clients,
+            client_by_addr: HashMap::with_capacity(max_clients),
+            client_by_id: HashMap::with_capacity(max_clients),
+            free_slots: (0..max_clients).rev().collect(),
+            pending_connections: pending_connections,
+            time: 0.0,
+            challenge_sequence: 0,
+            challenge_key: crypto::generate_key(),
+            token_history: TokenHistory::new(max_clients),
+            client_event_idx: 0,
+            pending_events: VecDeque::new(),
+            loopback_clients: 0,
+            workers: None,
+            send_slots: None,
+            send_clock: None
+        })
+    }
+
+    /// Gets the local port that this server is bound to, for dual-stack servers this is the IPv4 socket.
     pub fn get_local_addr(&self) -> Result<SocketAddr, io::Error> {
-        self.listen_socket.local_addr()
+        self.listen_sockets[0].local_addr()
+    }
+
+    /// Gets the addresses of every socket this server is listening on.
+    pub fn get_local_addrs(&self) -> Result<Vec<SocketAddr>, io::Error> {
+        self.listen_sockets.iter().map(|s| s.local_addr()).collect()
     }
 
     /// Sends a packet to `client_id` specified.
@@ -164,7 +319,214 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
 
         trace!("Sending packet to {} with length {}", client_id, packet.len());
 
-        self.send_packet(client_id, &packet::Packet::Payload(packet.len()), Some(packet))
+        match self.find_client_by_id(client_id) {
+            Some(client_idx) => {
+                let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+                self.send_payload(client_idx, packet, &mut scratch)
+            },
+            None => {
+                trace!("Unable to send packet, invalid client id {}", client_id);
+                Err(SendError::InvalidClientId)
+            }
+        }
+    }
+
+    /// Sends a packet to every connected client, returns the result of sending to each one.
+    ///
+    /// The payload is checked and the encode buffer set up once for all clients, which is cheaper than calling `send(..)` in a loop.
+    pub fn broadcast(&mut self, packet: &[u8]) -> Result<Vec<(ClientId, Result<usize, SendError>)>, SendError> {
+        self.send_where(packet, |_| true)
+    }
+
+    /// Sends a packet to every connected client other than those in `except`, such as the client a snapshot came from.
+    pub fn broadcast_except(&mut self, except: &[ClientId], packet: &[u8]) -> Result<Vec<(ClientId, Result<usize, SendError>)>, SendError> {
+        self.send_where(packet, |client_id| !except.contains(&client_id))
+    }
+
+    /// Sends a packet to each client in `client_ids`, returns a result for each in the same order.
+    /// Clients that aren't connected get `SendError::InvalidClientId` without affecting the rest.
+    pub fn send_to_many(&mut self, client_ids: &[ClientId], packet: &[u8]) -> Result<Vec<(ClientId, Result<usize, SendError>)>, SendError> {
+        if packet.len() == 0 || packet.len() > NETCODE_MAX_PAYLOAD_SIZE {
+            return Err(SendError::PacketSize)
+        }
+
+        let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+        let mut results = Vec::with_capacity(client_ids.len());
+        for &client_id in client_ids.iter() {
+            let result = match self.find_client_by_id(client_id) {
+                Some(client_idx) => self.send_payload(client_idx, packet, &mut scratch),
+                None => Err(SendError::InvalidClientId)
+            };
+
+            results.push((client_id, result));
+        }
+
+        Ok(results)
+    }
+
+    /// Sends any packets held back by a batching transport such as `BatchUdpSocket`, call once per frame after sending payloads and
+    /// draining `next_event(..)`. Does nothing for transports that send right away.
+    ///
+    /// With worker threads this also waits for every payload passed to `send(..)` to be encrypted and sends it.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.send_encoded(true)?;
+
+        for socket in self.listen_sockets.iter_mut() {
+            socket.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts and encrypts packets for connected clients on `count` worker threads, `0` goes back to doing it all on the calling thread.
+    ///
+    /// Clients are spread across workers by slot so each client's packets are still handled in order, and events are returned from
+    /// `next_event(..)` as before. Payloads passed to `send(..)` go out from `next_event(..)` or `flush()` once they're encrypted.
+    ///
+    /// To give the workers enough to do `next_event(..)` reads every packet that's ready before waiting on them, so receive timeouts
+    /// are cleared on the listen sockets. Packets still with the previous workers when this is called are dropped as if they were lost.
+    pub fn set_worker_threads(&mut self, count: usize) -> Result<(), io::Error> {
+        self.flush()?;
+        self.workers = None;
+
+        if count > 0 {
+            for socket in self.listen_sockets.iter_mut() {
+                socket.set_recv_timeout(None)?;
+            }
+
+            self.workers = Some(WorkerPool::new(count, self.protocol_id)?);
+        }
+
+        Ok(())
+    }
+
+    /// Gets the sequence number that will be used for the next payload sent to `client_id`, `None` if the client isn't connected.
+    ///
+    /// Until the client confirms the connection each payload is preceded by a keep alive, which takes the sequence before it.
+    pub fn next_packet_sequence(&self, client_id: ClientId) -> Option<u64> {
+        self.find_client_by_id(client_id)
+            .and_then(|idx| self.clients[idx].as_ref())
+            .map(|c| match c.loopback {
+                Some(ref loopback) => loopback.get_next_sequence(),
+                None if c.confirmed => c.channel.get_next_sequence(),
+                None => c.channel.get_next_sequence() + 1
+            })
+    }
+
+    /// Connects a local client to slot `client_idx` without a handshake, encryption or sockets. Pass the returned endpoint to
+    /// `Client::new_loopback(..)`, payloads are then exchanged through in-memory queues.
+    ///
+    /// The client shows up like any other, `ServerEvent::ClientConnect` is returned from the next call to `next_event(..)`.
+    /// Loopback clients never time out, they stay connected until either side disconnects.
+    pub fn connect_loopback_client(&mut self, client_idx: usize, client_id: ClientId, user_data: Option<&[u8; NETCODE_USER_DATA_BYTES]>)
+            -> Result<LoopbackEndpoint, LoopbackError> {
+        if client_idx >= self.clients.len() {
+            return Err(LoopbackError::InvalidClientIndex)
+        }
+
+        if self.clients[client_idx].is_some() {
+            return Err(LoopbackError::SlotInUse)
+        }
+
+        if let Some(_) = self.find_client_by_id(client_id) {
+            return Err(LoopbackError::ClientIdInUse)
+        }
+
+        let (server_end, client_end) = LoopbackEndpoint::pair(client_idx, self.clients.len());
+
+        //Channel is never used to send, the unspecified address can't match a real peer
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let key = [0; NETCODE_KEY_BYTES];
+
+        info!("Connected loopback client {} in slot {}", client_id, client_idx);
+
+        if let Some(pos) = self.free_slots.iter().position(|&idx| idx == client_idx) {
+            self.free_slots.remove(pos);
+        }
+
+        let channel = Channel::new(&key, &key, &addr, self.protocol_id, client_idx, self.clients.len(), 0, self.time);
+        let user_data = user_data.map_or([0; NETCODE_USER_DATA_BYTES], |d| *d);
+        self.add_client(client_idx, Connection {
+            client_id: client_id,
+            state: ConnectionState::Idle,
+            socket_idx: 0,
+            channel: channel,
+            user_data: user_data,
+            confirmed: true,
+            loopback: Some(server_end)
+        });
+
+        self.loopback_clients += 1;
+        self.pending_events.push_back(ServerEvent::ClientConnect(client_id));
+
+        Ok(client_end)
+    }
+
+    /// Returns true if `client_id` is connected through `connect_loopback_client(..)`.
+    pub fn is_loopback_client(&self, client_id: ClientId) -> bool {
+        self.find_client_by_id(client_id)
+            .and_then(|idx| self.clients[idx].as_ref())
+            .map_or(false, |c| c.loopback.is_some())
+    }
+
+    /// Gets the user data from the connect token of `client_id`, `None` if the client isn't connected.
+    pub fn client_user_data(&self, client_id: ClientId) -> Option<&[u8; NETCODE_USER_DATA_BYTES]> {
+        self.find_client_by_id(client_id)
+            .and_then(|idx| self.clients[idx].as_ref())
+            .map(|c| &c.user_data)
+    }
+
+    /// Disconnects `client_id`, sending redundant disconnect packets so the client is notified right away.
+    /// The client's slot is freed immediately and `ServerEvent::ClientDisconnect` is returned from the next call to `next_event(..)`.
+    pub fn disconnect_client(&mut self, client_id: ClientId) -> Result<(), SendError> {
+        match self.find_client_by_id(client_id) {
+            Some(idx) => self.disconnect_client_idx(idx),
+            None => {
+                trace!("Unable to disconnect, invalid client id {}", client_id);
+                Err(SendError::InvalidClientId)
+            }
+        }
+    }
+
+    /// Disconnects all clients, see `disconnect_client(..)`.
+    pub fn disconnect_all(&mut self) -> Result<(), SendError> {
+        let mut result = Ok(());
+
+        for idx in 0..self.clients.len() {
+            if self.clients[idx].is_some() {
+                let disconnect = self.disconnect_client_idx(idx);
+                if result.is_ok() {
+                    result = disconnect;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Gets the number of seconds until a keep-alive or timeout is due, `None` if there are no clients to update.
+    /// Clients still in the handshake count towards their timeout, their slot in the pending list frees up then.
+    ///
+    /// Event loops can sleep until a socket is readable or this much time has passed(rounding up) before calling `update(..)`
+    /// and `next_event(..)`. Loopback clients aren't counted, their queues need to be polled whenever the local client sends.
+    pub fn next_deadline(&self) -> Option<f64> {
+        if !self.pending_events.is_empty() {
+            return Some(0.0)
+        }
+
+        let time = self.time;
+        let pending = self.pending_connections.iter()
+            .filter_map(|p| p.as_ref())
+            .filter(|p| !p.channel.has_expired(time))
+            .map(|p| p.channel.expire_deadline());
+
+        self.clients.iter()
+            .filter_map(|c| c.as_ref())
+            .filter(|c| c.loopback.is_none())
+            .map(|c| c.channel.next_deadline())
+            .chain(pending)
+            .fold(None, |next: Option<f64>, deadline| Some(next.map_or(deadline, |next| next.min(deadline))))
+            .map(|deadline| (deadline - self.time).max(0.0))
     }
 
     /// Updates time elapsed since last server iteration.
@@ -172,6 +534,19 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         self.time += elapsed;
         self.client_event_idx = 0;
 
+        if let Some(ref clock) = self.send_clock {
+            clock.store(self.time.to_bits(), Ordering::Relaxed);
+        }
+
+        //Payloads from senders count as keep alives too
+        if let Some(ref slots) = self.send_slots {
+            for slot in slots.read().unwrap().values() {
+                if let Some(client) = self.clients[slot.client_idx].as_mut() {
+                    client.channel.mark_sent(f64::from_bits(slot.last_sent.load(Ordering::Relaxed)));
+                }
+            }
+        }
+
         Ok(())
     }
     
@@ -182,20 +557,44 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
             return Err(UpdateError::PacketBufferTooSmall)
         }
 
-        loop {
-            let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
-            let result = match self.listen_socket.recv_from(&mut scratch) {
-                Ok((len, addr)) => self.handle_io(&addr, &scratch[..len], out_packet),
-                Err(e) => match e.kind() {
-                    io::ErrorKind::WouldBlock => Ok(None),
-                    _ => Err(RecvError::SocketError(e).into())
+        if let Some(event) = self.pending_events.pop_front() {
+            if let ServerEvent::ClientConnect(client_id) = event {
+                if let Some(user_data) = self.client_user_data(client_id) {
+                    out_packet[..NETCODE_USER_DATA_BYTES].copy_from_slice(user_data);
                 }
-            };
+            }
 
-            if let Ok(None) = result {
-                break;
-            } else {
-                return result
+            return Ok(Some(event))
+        }
+
+        if self.loopback_clients > 0 {
+            if let Some(event) = self.next_loopback_event(out_packet) {
+                return Ok(Some(event))
+            }
+        }
+
+        if self.workers.is_some() {
+            self.send_encoded(false).map_err(SendError::from)?;
+
+            if let Some(event) = self.next_worker_event(out_packet)? {
+                return Ok(Some(event))
+            }
+        } else {
+            for socket_idx in 0..self.listen_sockets.len() {
+                let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+                let result = match self.listen_sockets[socket_idx].recv_from(&mut scratch) {
+                    Ok((len, addr)) => self.handle_io(socket_idx, &addr, &scratch[..len], out_packet),
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock => Ok(None),
+                        _ => Err(RecvError::SocketError(e).into())
+                    }
+                };
+
+                if let Ok(None) = result {
+                    continue;
+                } else {
+                    return result
+                }
             }
         }
 
@@ -204,9 +603,8 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
                 break;
             }
 
-            let clients = &mut self.clients;
-            let result = if let Some(client) = clients[self.client_event_idx].as_mut() {
-                Self::tick_client(self.time, client, &mut self.listen_socket)?
+            let result = if let Some(client) = self.clients[self.client_event_idx].as_mut() {
+                Self::tick_client(self.time, client, &mut self.listen_sockets[client.socket_idx])?
             } else {
                 TickResult::Noop
             };
@@ -217,13 +615,11 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
                     match state {
                         ConnectionState::TimedOut |
                         ConnectionState::Disconnected => {
-                            let client_id = clients[self.client_event_idx].as_ref().map_or(0, |c| c.client_id);
-                            clients[self.client_event_idx] = None;
-                            trace!("Client disconnected {}", client_id);
-                            Some(ServerEvent::ClientDisconnect(client_id))
+                            let idx = self.client_event_idx;
+                            self.remove_client(idx).map(|client_id| ServerEvent::ClientDisconnect(client_id))
                         },
                         _ => {
-                            if let Some(client) = clients[self.client_event_idx].as_mut() {
+                            if let Some(client) = self.clients[self.client_event_idx].as_mut() {
                                 client.state = state.clone();
                             }
 
@@ -232,7 +628,7 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
                     }
                 },
                 TickResult::SendKeepAlive => {
-                    let client_id = clients[self.client_event_idx].as_ref().map_or(0, |c| c.client_id);
+                    let client_id = self.clients[self.client_event_idx].as_ref().map_or(0, |c| c.client_id);
                     Some(ServerEvent::KeepAlive(client_id))
                 }
             };
@@ -247,22 +643,133 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         Ok(None)
     }
 
-    fn handle_io(&mut self, addr: &SocketAddr, data: &[u8], out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Option<ServerEvent>, UpdateError> {
-        match self.find_client_by_addr(addr) {
-            None => {
-                trace!("New data on listening socket");
+    fn next_loopback_event(&mut self, out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Option<ServerEvent> {
+        for idx in 0..self.clients.len() {
+            let (client_id, received, disconnected) = match self.clients[idx].as_ref() {
+                Some(&Connection { client_id, loopback: Some(ref loopback), .. }) => (client_id, loopback.recv(out_packet), loopback.is_disconnected()),
+                _ => continue
+            };
+
+            if let Some((len, sequence)) = received {
+                return Some(ServerEvent::Packet(client_id, len, sequence))
+            }
+
+            if disconnected {
+                return self.remove_client(idx).map(|client_id| ServerEvent::ClientDisconnect(client_id))
+            }
+        }
+
+        None
+    }
 
-                match packet::decode(data, self.protocol_id, None, out_packet) {
-                    Ok(packet) => match packet.1 {
-                        packet::Packet::ConnectionRequest(req) => self.handle_client_connect(addr, &req),
-                        packet => {
-                            trace!("Expected Connection Request but got packet type {}", packet.get_type_id());
+    /// Reads every packet that's ready handing those from connected clients to the workers, then returns events as they're decrypted.
+    /// Handshake packets are still handled here as they're read.
+    fn next_worker_event(&mut self, out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Option<ServerEvent>, UpdateError> {
+        loop {
+            for socket_idx in 0..self.listen_sockets.len() {
+                while self.workers.as_ref().map_or(false, |w| w.decodes_in_flight() < MAX_DECODES_IN_FLIGHT) {
+                    let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+                    let (len, addr) = match self.listen_sockets[socket_idx].recv_from(&mut scratch) {
+                        Ok(read) => read,
+                        Err(e) => match e.kind() {
+                            io::ErrorKind::WouldBlock => break,
+                            _ => return Err(RecvError::SocketError(e).into())
+                        }
+                    };
+
+                    match self.find_client_by_addr(&addr) {
+                        Some(client_idx) => self.queue_decode(client_idx, &addr, &scratch[..len]),
+                        None => if let Some(event) = self.handle_io(socket_idx, &addr, &scratch[..len], out_packet)? {
+                            return Ok(Some(event))
+                        }
+                    }
+                }
+            }
+
+            let decoded = match self.workers.as_mut().and_then(|w| w.next_decoded()) {
+                Some(decoded) => decoded,
+                None => return Ok(None)
+            };
+
+            if let Some(event) = self.handle_decoded(decoded, out_packet)? {
+                return Ok(Some(event))
+            }
+        }
+    }
+
+    fn queue_decode(&mut self, client_idx: usize, addr: &SocketAddr, data: &[u8]) {
+        if data.len() == 0 {
+            return
+        }
+
+        if let (Some(workers), Some(client)) = (self.workers.as_mut(), self.clients[client_idx].as_ref()) {
+            workers.decode(DecodeJob {
+                client_idx: client_idx,
+                client_id: client.client_id,
+                addr: addr.clone(),
+                key: *client.channel.get_recv_key(),
+                data: data.to_vec()
+            });
+        }
+    }
+
+    fn handle_decoded(&mut self, decoded: Decoded, out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Option<ServerEvent>, UpdateError> {
+        let time = self.time;
+
+        //Slot may have been freed or handed to someone else while the packet was with a worker
+        let result = match self.clients[decoded.client_idx].as_mut() {
+            Some(client) if client.client_id == decoded.client_id && *client.channel.get_addr() == decoded.addr => {
+                match decoded.result {
+                    Ok((sequence, packet)) => client.channel.accept(time, sequence).map(|_| (sequence, packet)),
+                    Err(e) => Err(e.into())
+                }
+            },
+            _ => return Ok(None)
+        };
+
+        if let Ok((_, packet::Packet::Payload(len))) = result {
+            out_packet[..len].copy_from_slice(&decoded.payload);
+        }
+
+        self.handle_recv_result(decoded.client_idx, result)
+    }
+
+    /// Sends payloads the workers have finished encrypting, if `wait` is set this waits for all of them.
+    fn send_encoded(&mut self, wait: bool) -> Result<(), io::Error> {
+        while let Some(encoded) = self.workers.as_mut().and_then(|w| w.next_encoded(wait)) {
+            match encoded.result {
+                Ok(data) => {
+                    self.listen_sockets[encoded.socket_idx].send_to(&data, &encoded.addr)?;
+                },
+                Err(e) => info!("Failed to encode packet: {:?}", e)
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_io(&mut self, socket_idx: usize, addr: &SocketAddr, data: &[u8], out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> Result<Option<ServerEvent>, UpdateError> {
+        match self.find_client_by_addr(addr) {
+            None => match self.find_pending_by_addr(addr) {
+                Some(pending_idx) if !packet::is_connection_request(data) => {
+                    trace!("New data on pending connection {}", pending_idx);
+                    self.handle_pending_packet(pending_idx, data, out_packet)
+                },
+                _ => {
+                    trace!("New data on listening socket");
+
+                    match packet::decode(data, self.protocol_id, None, out_packet) {
+                        Ok(packet) => match packet.1 {
+                            packet::Packet::ConnectionRequest(req) => self.handle_client_connect(socket_idx, addr, &req),
+                            packet => {
+                                trace!("Expected Connection Request but got packet type {}", packet.get_type_id());
+                                Ok(None)
+                            }
+                        },
+                        Err(e) => {
+                            trace!("Failed to decode connect packet: {:?}", e);
                             Ok(None)
                         }
-                    },
-                    Err(e) => {
-                        trace!("Failed to decode connect packet: {:?}", e);
-                        Ok(None)
                     }
                 }
             },
@@ -274,31 +781,57 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         }
     }
 
-    fn handle_client_connect(&mut self, addr: &SocketAddr, request: &packet::ConnectionRequestPacket) -> Result<Option<ServerEvent>, UpdateError> {
-        if let Some(private_data) = Self::validate_client_token(self.protocol_id, &self.listen_addr, &self.connect_key, request) {
-            //See if we already have this connection
+    fn handle_client_connect(&mut self, socket_idx: usize, addr: &SocketAddr, request: &packet::ConnectionRequestPacket) -> Result<Option<ServerEvent>, UpdateError> {
+        if let Some(private_data) = Self::validate_client_token(self.protocol_id, &self.public_addrs, &self.connect_key, request) {
+            //Make sure this token hasn't been captured and replayed from somewhere else
+            let mut mac = [0; NETCODE_MAC_BYTES];
+            mac.copy_from_slice(&request.private_data[NETCODE_CONNECT_TOKEN_PRIVATE_BYTES - NETCODE_MAC_BYTES..]);
+
+            if !self.token_history.find_or_add(&mac, addr, self.time) {
+                info!("Connect token already used by another address, rejecting {:?}", addr);
+                return Ok(Some(ServerEvent::RejectedClient))
+            }
+
             if let Some(_) = self.find_client_by_id(private_data.client_id) {
-                trace!("Client already exists, skipping socket creation");
-            } else {
-                //Find open index
-                match self.clients.iter().position(|v| v.is_none()) {
-                    Some(idx) => {
-                        let conn = Connection {
-                            client_id: private_data.client_id,
-                            state: ConnectionState::PendingResponse,
-                            channel: Channel::new(&private_data.server_to_client_key, &private_data.client_to_server_key, addr, self.protocol_id, idx, self.clients.len())
-                        };
-
-                        trace!("Accepted connection {:?}", addr);
-                        self.clients[idx] = Some(conn);
-                    },
-                    None => {
-                        self.send_denied_packet(&addr, &private_data.server_to_client_key)?;
-                        trace!("Tried to accept new client but max clients connected: {}", self.clients.len());
-                        return Ok(Some(ServerEvent::ClientSlotFull))
+                trace!("Client {} is already connected, ignoring request", private_data.client_id);
+                return Ok(None)
+            }
+
+            //Only check for a slot here, it isn't assigned until the challenge response comes back
+            let existing_idx = self.find_pending_by_addr(addr);
+            if self.free_slots.is_empty() {
+                trace!("Tried to accept new client but max clients connected: {}", self.clients.len());
+                return self.deny_connection(existing_idx, socket_idx, addr, &private_data.server_to_client_key).map(|_| Some(ServerEvent::ClientSlotFull))
+            }
+
+            let pending_idx = match existing_idx {
+                Some(idx) if self.pending_connections[idx].as_ref().map_or(false, |p| p.token_mac == mac) => idx,
+                _ => {
+                    //Reuse any slot this address had for another token, otherwise take a free or expired one
+                    let time = self.time;
+                    let free_idx = existing_idx.or_else(|| self.pending_connections.iter()
+                        .position(|v| v.as_ref().map_or(true, |p| p.channel.has_expired(time))));
+
+                    match free_idx {
+                        Some(idx) => {
+                            trace!("Accepted connection request {:?}", addr);
+
+                            self.pending_connections[idx] = Some(PendingConnection {
+                                client_id: private_data.client_id,
+                                token_mac: mac,
+                                socket_idx: socket_idx,
+                                channel: Channel::new(&private_data.server_to_client_key, &private_data.client_to_server_key, addr, self.protocol_id, 0, self.clients.len(), private_data.timeout_sec, self.time)
+                            });
+
+                            idx
+                        },
+                        None => {
+                            info!("Too many pending connections, ignoring request from {:?}", addr);
+                            return Ok(None)
+                        }
                     }
                 }
-            }
+            };
 
             self.challenge_sequence += 1;
 
@@ -311,7 +844,9 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
                 &self.challenge_key)?;
 
             //Send challenge token
-            self.send_packet(private_data.client_id, &packet::Packet::Challenge(challenge), None)?;
+            if let Some(pending) = self.pending_connections[pending_idx].as_mut() {
+                pending.channel.send(self.time, &packet::Packet::Challenge(challenge), None, &mut self.listen_sockets[pending.socket_idx])?;
+            }
 
             Ok(None)
         } else {
@@ -320,35 +855,233 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         }
     }
 
-    fn send_packet(&mut self, client_id: ClientId, packet: &packet::Packet, payload: Option<&[u8]>) -> Result<usize, SendError> {
-        let socket = &mut self.listen_socket;
-        let clients = &mut self.clients;
+    fn send_where<F>(&mut self, payload: &[u8], filter: F) -> Result<Vec<(ClientId, Result<usize, SendError>)>, SendError> where F: Fn(ClientId) -> bool {
+        if payload.len() == 0 || payload.len() > NETCODE_MAX_PAYLOAD_SIZE {
+            return Err(SendError::PacketSize)
+        }
 
-        //Get client idx
-        let client = clients.iter().position(|v| v.as_ref().map_or(false, |ref c| c.client_id == client_id))
-            //Then turn it into a reference
-            .map(|idx| clients[idx].as_mut()).and_then(|c| c);
+        let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+        let mut results = vec!();
+        for client_idx in 0..self.clients.len() {
+            let client_id = match self.clients[client_idx] {
+                Some(ref client) if filter(client.client_id) => client.client_id,
+                _ => continue
+            };
 
-        if let Some(client) = client {
-            return client.channel.send(self.time, packet, payload, socket)
+            results.push((client_id, self.send_payload(client_idx, payload, &mut scratch)));
         }
 
-        trace!("Unable to send packet, invalid client id {}", client_id);
+        Ok(results)
+    }
 
-        Err(SendError::InvalidClientId)
-   }
+    /// Sends `payload` to the client in `client_idx`, encoding into `scratch` so sends to many clients can share one buffer.
+    fn send_payload(&mut self, client_idx: usize, payload: &[u8], scratch: &mut [u8; NETCODE_MAX_PACKET_SIZE]) -> Result<usize, SendError> {
+        let time = self.time;
+        let client = match self.clients[client_idx].as_mut() {
+            Some(client) => client,
+            None => return Err(SendError::InvalidClientId)
+        };
+
+        if let Some(ref loopback) = client.loopback {
+            return loopback.send(payload).map(|_| payload.len())
+        }
+
+        //Client may have missed the keep alive that completes its connection, so keep sending them until it talks to us
+        let socket = &mut self.listen_sockets[client.socket_idx];
+        if !client.confirmed {
+            client.channel.send_keep_alive(time, socket)?;
+        }
+
+        match self.workers {
+            Some(ref mut workers) => {
+                let sequence = client.channel.reserve_sequence(time);
+
+                workers.encode(client_idx, EncodeJob {
+                    socket_idx: client.socket_idx,
+                    addr: client.channel.get_addr().clone(),
+                    sequence: sequence,
+                    key: *client.channel.get_send_key(),
+                    payload: payload.to_vec()
+                });
+
+                Ok(packet::payload_packet_len(sequence, payload.len()))
+            },
+            None => client.channel.send_with_scratch(time, scratch, &packet::Packet::Payload(payload.len()), Some(payload), socket)
+        }
+    }
+
+    fn disconnect_client_idx(&mut self, client_idx: usize) -> Result<(), SendError> {
+        let mut result = Ok(());
+
+        if let Some(client) = self.clients[client_idx].as_mut() {
+            trace!("Sending disconnect to {}", client.client_id);
+
+            //Loopback clients see the disconnect when their slot is removed
+            let count = if client.loopback.is_some() { 0 } else { NETCODE_NUM_DISCONNECT_PACKETS };
+            for _ in 0..count {
+                if let Err(e) = client.channel.send(self.time, &packet::Packet::Disconnect, None, &mut self.listen_sockets[client.socket_idx]) {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        if let Some(client_id) = self.remove_client(client_idx) {
+            self.pending_events.push_back(ServerEvent::ClientDisconnect(client_id));
+        }
+
+        result
+    }
+
+    fn add_client(&mut self, client_idx: usize, client: Connection) {
+        if client.loopback.is_none() {
+            self.client_by_addr.insert(*client.channel.get_addr(), client_idx);
+        }
+        self.client_by_id.insert(client.client_id, client_idx);
+        if let Some(ref slots) = self.send_slots {
+            slots.write().unwrap().insert(client.client_id, Arc::new(SendSlot::new(client_idx, &client)));
+        }
+        self.clients[client_idx] = Some(client);
+    }
+
+    fn remove_client(&mut self, client_idx: usize) -> Option<ClientId> {
+        let loopback_clients = &mut self.loopback_clients;
+        let client_by_addr = &mut self.client_by_addr;
+        let client_by_id = &mut self.client_by_id;
+        let free_slots = &mut self.free_slots;
+        let send_slots = &self.send_slots;
+
+        self.clients[client_idx].take().map(|client| {
+            trace!("Client disconnected {}", client.client_id);
+
+            if client.loopback.is_none() {
+                client_by_addr.remove(client.channel.get_addr());
+            }
+            client_by_id.remove(&client.client_id);
+            free_slots.push(client_idx);
+
+            if let Some(ref slots) = *send_slots {
+                slots.write().unwrap().remove(&client.client_id);
+            }
+
+            if let Some(loopback) = client.loopback {
+                loopback.disconnect();
+                *loopback_clients -= 1;
+            }
+
+            client.client_id
+        })
+    }
 
-    fn send_denied_packet(&mut self, addr: &SocketAddr, key: &[u8; NETCODE_KEY_BYTES]) -> Result<(), SendError> {
-        //id + sequence
-        let mut packet = [0; 1 + 8];
-        packet::encode(&mut packet[..], self.protocol_id, &packet::Packet::ConnectionDenied, Some((0, key)), None)?;
+    fn handle_pending_packet(&mut self,
+            pending_idx: usize,
+            packet: &[u8],
+            out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE])
+                -> Result<Option<ServerEvent>, UpdateError> {
+        let decoded = if let Some(pending) = self.pending_connections[pending_idx].as_mut() {
+            match pending.channel.recv(self.time, packet, out_packet) {
+                Ok((_, packet)) => packet,
+                Err(e) => {
+                    trace!("Failed to decode packet from pending connection: {:?}", e);
+                    return Ok(None)
+                }
+            }
+        } else {
+            return Ok(None)
+        };
 
-        self.listen_socket.send_to(&packet[..], addr).map_err(|e| e.into()).map(|_| ())
+        match decoded {
+            packet::Packet::Response(resp) => self.handle_challenge_response(pending_idx, &resp, out_packet),
+            p => {
+                info!("Unexpected packet type when waiting for response {}", p.get_type_id());
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_challenge_response(&mut self,
+            pending_idx: usize,
+            resp: &packet::ResponsePacket,
+            out_packet: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE])
+                -> Result<Option<ServerEvent>, UpdateError> {
+        let token = match resp.decode(&self.challenge_key) {
+            Ok(token) => token,
+            Err(e) => {
+                info!("Failed to decode challenge token: {:?}", e);
+                return Ok(None)
+            }
+        };
+
+        //Challenge tokens are opaque to clients so make sure one wasn't lifted from another handshake
+        if self.pending_connections[pending_idx].as_ref().map_or(true, |p| p.client_id != token.client_id) {
+            info!("Challenge response didn't match pending client {}", token.client_id);
+            return Ok(None)
+        }
+
+        if let Some(_) = self.find_client_by_id(token.client_id) {
+            trace!("Client {} is already connected, ignoring response", token.client_id);
+            return Ok(None)
+        }
+
+        match self.free_slots.pop() {
+            Some(idx) => {
+                if let Some(mut pending) = self.pending_connections[pending_idx].take() {
+                    pending.channel.set_client_idx(idx, self.clients.len());
+
+                    info!("Accepted connection {:?}", pending.channel.get_addr());
+
+                    self.add_client(idx, Connection {
+                        client_id: pending.client_id,
+                        state: ConnectionState::Idle,
+                        socket_idx: pending.socket_idx,
+                        channel: pending.channel,
+                        user_data: token.user_data,
+                        confirmed: false,
+                        loopback: None
+                    });
+                }
+
+                if let Some(client) = self.clients[idx].as_mut() {
+                    client.channel.send_keep_alive(self.time, &mut self.listen_sockets[client.socket_idx])?;
+                }
+
+                out_packet[..NETCODE_USER_DATA_BYTES].copy_from_slice(&token.user_data);
+
+                Ok(Some(ServerEvent::ClientConnect(token.client_id)))
+            },
+            None => {
+                trace!("Client responded but max clients connected: {}", self.clients.len());
+
+                let (socket_idx, addr) = match self.pending_connections[pending_idx].as_ref() {
+                    Some(pending) => (pending.socket_idx, pending.channel.get_addr().clone()),
+                    None => return Ok(None)
+                };
+
+                //Key is unused since the pending channel already has it
+                self.deny_connection(Some(pending_idx), socket_idx, &addr, &[0; NETCODE_KEY_BYTES])?;
+                Ok(Some(ServerEvent::ClientSlotFull))
+            }
+        }
+    }
+
+    fn deny_connection(&mut self, pending_idx: Option<usize>, socket_idx: usize, addr: &SocketAddr, key: &[u8; NETCODE_KEY_BYTES]) -> Result<(), UpdateError> {
+        //If we've already talked to this client we need to keep using the same channel so sequence numbers keep increasing
+        match pending_idx.and_then(|idx| self.pending_connections[idx].take()) {
+            Some(mut pending) => pending.channel.send(self.time, &packet::Packet::ConnectionDenied, None, &mut self.listen_sockets[pending.socket_idx]).map(|_| ()),
+            None => self.send_denied_packet(socket_idx, addr, key)
+        }.map_err(|e| e.into())
+    }
+
+    fn send_denied_packet(&mut self, socket_idx: usize, addr: &SocketAddr, key: &[u8; NETCODE_KEY_BYTES]) -> Result<(), SendError> {
+        let mut packet = [0; NETCODE_MAX_PACKET_SIZE];
+        let len = packet::encode(&mut packet[..], self.protocol_id, &packet::Packet::ConnectionDenied, Some((0, key)), None)?;
+
+        self.listen_sockets[socket_idx].send_to(&packet[..len], addr).map_err(|e| e.into()).map(|_| ())
     }
 
     fn validate_client_token(
             protocol_id: u64,
-            host: &SocketAddr,
+            public_addrs: &[SocketAddr],
             private_key: &[u8; NETCODE_KEY_BYTES],
             req: &packet::ConnectionRequestPacket) -> Option<token::PrivateData> {
         if req.version != *NETCODE_VERSION_STRING {
@@ -365,9 +1098,7 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         }
 
         if let Ok(v) = token::PrivateData::decode(&req.private_data, protocol_id, req.token_expire, req.sequence, private_key) {
-            let has_host = v.hosts.get().any(|thost| {
-                    thost == *host || (host.port() == 0 && thost.ip() == host.ip())
-                });
+            let has_host = v.hosts.get().any(|thost| public_addrs.contains(&thost));
 
             if !has_host {
                 info!("Client connected but didn't contain host's address.");
@@ -381,16 +1112,14 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         }
    }
 
-    fn tick_client(time: f64, client: &mut Connection, socket: &mut I) -> Result<TickResult, UpdateError> {
+    fn tick_client(time: f64, client: &mut Connection, socket: &mut T) -> Result<TickResult, UpdateError> {
+        //Loopback clients can't time out and don't need keep alives
+        if client.loopback.is_some() {
+            return Ok(TickResult::Noop)
+        }
+
         let state = &client.state;
         let result = match *state {
-            ConnectionState::PendingResponse => {
-                match client.channel.update(time, socket, false)? {
-                    channel::UpdateResult::Expired => TickResult::StateChange(ConnectionState::TimedOut),
-                    channel::UpdateResult::SentKeepAlive => TickResult::SendKeepAlive,
-                    channel::UpdateResult::Noop => TickResult::Noop
-                }
-            },
             ConnectionState::Idle => {
                 match client.channel.update(time, socket, true)? {
                     channel::UpdateResult::Expired => TickResult::StateChange(ConnectionState::TimedOut),
@@ -414,32 +1143,47 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
         }
 
         trace!("Handling packet from client");
-        let (client_id, mut state, addr, decoded) = if let Some(client) = self.clients[client_idx].as_mut() {
-             let decoded = match client.channel.recv(self.time, packet, out_packet) {
-                Ok(packet) => packet,
+        let result = match self.clients[client_idx].as_mut() {
+            Some(client) => client.channel.recv(self.time, packet, out_packet),
+            None => return Ok(None)
+        };
+
+        self.handle_recv_result(client_idx, result)
+    }
+
+    fn handle_recv_result(&mut self, client_idx: usize, result: Result<(u64, packet::Packet), RecvError>) -> Result<Option<ServerEvent>, UpdateError> {
+        let (client_id, mut state, sequence, decoded) = if let Some(client) = self.clients[client_idx].as_ref() {
+             let (sequence, decoded) = match result {
+                Ok((sequence, packet)) => (sequence, Some(packet)),
                 Err(RecvError::DuplicateSequence) => return Ok(Some(ServerEvent::ReplayRejected(client.client_id))),
                 Err(e) => {
                     info!("Failed to decode packet: {:?}", e);
-                    client.state = ConnectionState::Disconnected;
-
-                    return Ok(Some(ServerEvent::ClientDisconnect(client.client_id)))
+                    (0, None)
                 }
             };
  
-            (client.client_id, client.state.clone(), client.channel.get_addr().clone(), decoded)
+            (client.client_id, client.state.clone(), sequence, decoded)
         } else {
             return Ok(None)
         };
 
+        let decoded = match decoded {
+            Some(decoded) => decoded,
+            None => return Ok(self.remove_client(client_idx).map(|client_id| ServerEvent::ClientDisconnect(client_id)))
+        };
+
         //Update client state with any recieved packet
+        let mut confirmed = false;
         let event = match state {
             ConnectionState::Idle => {
                 match decoded {
                     packet::Packet::Payload(len) => {
                         trace!("Received payload packet from {} with size {}", client_id, len);
-                        Some(ServerEvent::Packet(client_id, len))
+                        confirmed = true;
+                        Some(ServerEvent::Packet(client_id, len, sequence))
                     },
                     packet::Packet::KeepAlive(_) => {
+                        confirmed = true;
                         Some(ServerEvent::KeepAlive(client_id))
                     },
                     packet::Packet::Disconnect => {
@@ -452,59 +1196,128 @@ impl<I,S> Server<I,S> where I: SocketProvider<I,S> {
                     }
                 }
              },
-            ConnectionState::PendingResponse => {
-                match decoded {
-                    packet::Packet::Response(resp) => {
-                        let token = resp.decode(&self.challenge_key)?;
-                        out_packet[..NETCODE_USER_DATA_BYTES].copy_from_slice(&token.user_data);
-
-                        if let Some(client) = self.clients[client_idx].as_mut() {
-                            client.channel.send_keep_alive(self.time, &mut self.listen_socket)?;
-                        }
-
-                        info!("client response");
-
-                        state = ConnectionState::Idle;
-                        Some(ServerEvent::ClientConnect(token.client_id))
-                    },
-                    packet::Packet::ConnectionRequest(req) => {
-                        self.handle_client_connect(&addr, &req)?;
-                        None
-                    },
-                    p => {
-                        info!("Unexpected packet type when waiting for response {}", p.get_type_id());
-                        None
-                    }
-                }
-            },
             _ => None
         };
 
         trace!("state {:?}", &state);
 
-        if let Some(client) = self.clients[client_idx].as_mut() {
-            client.state = state;
+        match state {
+            ConnectionState::Disconnected => {
+                self.remove_client(client_idx);
+            },
+            _ => if let Some(client) = self.clients[client_idx].as_mut() {
+                client.state = state;
+
+                if confirmed && !client.confirmed {
+                    client.confirmed = true;
+
+                    if let Some(slot) = self.send_slots.as_ref().and_then(|s| s.read().unwrap().get(&client_id).cloned()) {
+                        slot.confirmed.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
         }
 
         Ok(event)
     }
 
     fn find_client_by_id(&self, id: ClientId) -> Option<usize> {
-        self.clients.iter().position(|v| v.as_ref().map_or(false, |ref c| c.client_id == id))
+        self.client_by_id.get(&id).cloned()
     }
 
     fn find_client_by_addr(&self, addr: &SocketAddr) -> Option<usize> {
-        self.clients.iter().position(|v| v.as_ref().map_or(false, |ref c| *c.channel.get_addr() == *addr))
+        self.client_by_addr.get(addr).cloned()
     }
 
-    #[cfg(test)]
-    pub fn get_socket_state(&mut self) -> &mut S {
-        &mut self.socket_state
+    fn find_pending_by_addr(&self, addr: &SocketAddr) -> Option<usize> {
+        let time = self.time;
+        self.pending_connections.iter().position(|v| v.as_ref().map_or(false, |ref p| *p.channel.get_addr() == *addr && !p.channel.has_expired(time)))
+    }
+
+    /// Sockets we're listening on, used to wait for readiness when driven by an event loop.
+    #[cfg(feature = "async-tokio")]
+    pub(crate) fn transports(&self) -> &[T] {
+        &self.listen_sockets
     }
 
     #[cfg(test)]
     pub fn set_read_timeout(&mut self, duration: Option<Duration>) -> Result<(), io::Error> {
-        self.listen_socket.set_recv_timeout(duration)
+        for socket in self.listen_sockets.iter_mut() {
+            socket.set_recv_timeout(duration)?;
+        }
+
+        Ok(())
+    }
+
+    /// Lets tests bind port 0 and advertise whichever ports they got.
+    #[cfg(test)]
+    pub fn set_public_addrs(&mut self, public_addrs: Vec<SocketAddr>) {
+        self.public_addrs = public_addrs;
+    }
+}
+
+impl<T> Server<T> where T: CloneTransport {
+    /// Creates a handle that sends payloads to clients from other threads while this server keeps receiving, each handle has its
+    /// own copies of the sockets. Sequence numbers and keys are shared with the server so both can send to the same client.
+    ///
+    /// Keep-alives, disconnects and handshakes are still sent by the server, so keep calling `update(..)` and `next_event(..)`.
+    /// Payloads from senders count towards keep-alives from the next `update(..)`.
+    pub fn sender(&mut self) -> Result<ServerSender<T>, io::Error> {
+        let sockets = self.listen_sockets.iter().map(|s| s.try_clone()).collect::<Result<Vec<_>, _>>()?;
+
+        let slots = match self.send_slots {
+            Some(ref slots) => slots.clone(),
+            None => {
+                let slots = self.clients.iter().enumerate()
+                    .filter_map(|(idx, c)| c.as_ref().map(|c| (c.client_id, Arc::new(SendSlot::new(idx, c)))))
+                    .collect::<HashMap<_, _>>();
+
+                let slots = Arc::new(RwLock::new(slots));
+                self.send_slots = Some(slots.clone());
+                slots
+            }
+        };
+
+        let time = self.time;
+        let clock = self.send_clock.get_or_insert_with(|| Arc::new(AtomicU64::new(time.to_bits()))).clone();
+
+        Ok(ServerSender::new(sockets, slots, clock, self.protocol_id, self.clients.len()))
+    }
+}
+
+/// Raw fd of the first listen socket, dual-stack servers have more than one so register them all through `mio` instead.
+#[cfg(unix)]
+impl<T> AsRawFd for Server<T> where T: Transport + AsRawFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listen_sockets[0].as_raw_fd()
+    }
+}
+
+/// Registers every listen socket under the same token.
+#[cfg(all(unix, feature = "mio"))]
+impl<T> mio::event::Source for Server<T> where T: Transport + AsRawFd {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> Result<(), io::Error> {
+        for socket in self.listen_sockets.iter() {
+            SourceFd(&socket.as_raw_fd()).register(registry, token, interests)?;
+        }
+
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> Result<(), io::Error> {
+        for socket in self.listen_sockets.iter() {
+            SourceFd(&socket.as_raw_fd()).reregister(registry, token, interests)?;
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> Result<(), io::Error> {
+        for socket in self.listen_sockets.iter() {
+            SourceFd(&socket.as_raw_fd()).deregister(registry)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -515,6 +1328,7 @@ mod test {
     use token;
     use super::*;
 
+    #[cfg(feature = "capi-tests")]
     use socket::capi_simulator::*;
 
     use std::net::UdpSocket;
@@ -523,29 +1337,35 @@ mod test {
     const MAX_CLIENTS: usize = 256;
     const CLIENT_ID: u64 = 0xFFEEDD;
 
-    struct TestHarness<I,S> where I: SocketProvider<I,S> {
+    struct TestHarness<T> where T: Transport {
         next_sequence: u64,
-        server: Server<I,S>,
+        server: Server<T>,
         private_key: [u8; NETCODE_KEY_BYTES],
-        socket: I,
+        socket: T,
         connect_token: token::ConnectToken
     }
 
-    impl<S,I> TestHarness<I,S> where I: SocketProvider<I,S> {
-        pub fn new(port: Option<u16>) -> TestHarness<I,S> {
+    impl<T> TestHarness<T> where T: BindTransport {
+        pub fn new() -> TestHarness<T> {
+            let addr = Self::str_to_addr("127.0.0.1:0");
+            Self::with_transports(T::bind(&addr).unwrap(), T::bind(&addr).unwrap())
+        }
+    }
+
+    impl<T> TestHarness<T> where T: Transport {
+        pub fn with_transports(server_socket: T, socket: T) -> TestHarness<T> {
             let private_key = crypto::generate_key();
 
-            let addr = format!("127.0.0.1:{}", port.unwrap_or(0));
-            let mut server = Server::<I,S>::new(&addr, MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+            let mut server = Server::with_transport(server_socket, MAX_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
             server.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
-            let socket = I::bind(&Self::str_to_addr(&addr), server.get_socket_state()).unwrap();
+            let connect_token = Self::generate_connect_token(&private_key, &server.get_local_addr().unwrap().to_string());
 
             TestHarness {
                 next_sequence: 0,
                 server: server,
                 private_key: private_key,
                 socket: socket,
-                connect_token: Self::generate_connect_token(&private_key, addr.as_str())
+                connect_token: connect_token
             }
         }
 
@@ -565,14 +1385,16 @@ mod test {
                                 None).unwrap()
         }
 
-        pub fn replace_connect_token(&mut self, addr: &str, key: Option<&[u8; NETCODE_KEY_BYTES]>) {
-            self.connect_token = Self::generate_connect_token(key.unwrap_or(&self.private_key), addr);
+        pub fn replace_connect_token_user_data(&mut self, user_data: &[u8; NETCODE_USER_DATA_BYTES]) {
+            let addr = self.server.get_local_addr().unwrap();
+            self.connect_token = token::ConnectToken::generate([addr].iter().cloned(), &self.private_key, 30, 0, PROTOCOL_ID, CLIENT_ID, Some(user_data)).unwrap();
         }
 
-        pub fn get_socket_state(&mut self) -> &mut S {
-            self.server.get_socket_state()
+        pub fn replace_connect_token(&mut self, addr: &str, key: Option<&[u8; NETCODE_KEY_BYTES]>) {
+            self.connect_token = Self::generate_connect_token(key.unwrap_or(&self.private_key), addr);
         }
 
+        #[cfg(feature = "capi-tests")]
         pub fn get_connect_token(&mut self) -> &token::ConnectToken {
             &self.connect_token
         }
@@ -583,6 +1405,11 @@ mod test {
         }
 
         pub fn send_connect_packet(&mut self) {
+            let (len, data) = self.generate_connect_packet();
+            self.socket.send_to(&data[..len], &self.server.get_local_addr().unwrap()).unwrap();
+        }
+
+        fn generate_connect_packet(&self) -> (usize, [u8; NETCODE_MAX_PACKET_SIZE]) {
             let mut private_data = [0; NETCODE_CONNECT_TOKEN_PRIVATE_BYTES];
             private_data.copy_from_slice(&self.connect_token.private_data);
 
@@ -596,7 +1423,8 @@ mod test {
 
             let mut data = [0; NETCODE_MAX_PACKET_SIZE];
             let len = packet::encode(&mut data, PROTOCOL_ID, &packet, None, None).unwrap();
-            self.socket.send_to(&data[..len], &self.server.get_local_addr().unwrap()).unwrap();
+
+            (len, data)
         }
         
         fn validate_challenge(&mut self) {
@@ -668,20 +1496,20 @@ mod test {
             self.socket.send_to(&data[..len], &self.server.get_local_addr().unwrap()).unwrap();
         }
 
-        fn validate_recv_payload(&mut self, payload: &[u8]) {
+        fn validate_recv_payload(&mut self, payload: &[u8]) -> u64 {
             self.server.update(0.0).unwrap();
             let mut data = [0; NETCODE_MAX_PAYLOAD_SIZE];
 
             loop {
                 match self.server.next_event(&mut data) {
-                    Ok(Some(ServerEvent::Packet(cid, len))) => {
+                    Ok(Some(ServerEvent::Packet(cid, len, sequence))) => {
                         assert_eq!(cid, CLIENT_ID);
                         assert_eq!(payload.len(), len);
                         for i in 0..len {
                             assert_eq!(payload[i], data[i]);
                         }
 
-                        break;
+                        return sequence
                     },
                     Ok(Some(ServerEvent::KeepAlive(cid))) => {
                         assert_eq!(cid, CLIENT_ID);
@@ -691,6 +1519,14 @@ mod test {
             }
         }
 
+        fn read_packet(&mut self, payload: &mut [u8; NETCODE_MAX_PAYLOAD_SIZE]) -> (u64, Packet) {
+            let mut data = [0; NETCODE_MAX_PACKET_SIZE];
+            self.socket.set_recv_timeout(Some(Duration::from_secs(15))).unwrap();
+            let (read,_) = self.socket.recv_from(&mut data).unwrap();
+
+            packet::decode(&data[..read], PROTOCOL_ID, Some(&self.connect_token.server_to_client_key), payload).unwrap()
+        }
+
         fn validate_send_payload(&mut self, payload: &[u8]) {
             let mut data = [0; NETCODE_MAX_PACKET_SIZE];
             self.socket.set_recv_timeout(Some(Duration::from_secs(15))).unwrap();
@@ -719,15 +1555,16 @@ mod test {
 
         LogBuilder::new().filter(None, LogLevelFilter::Trace).init().unwrap();
 
-        use capi::*;
+        #[cfg(feature = "capi-tests")]
         unsafe {
+            use capi::*;
             netcode_log_level(NETCODE_LOG_LEVEL_DEBUG as i32);
         }
     }
 
     #[test]
     fn test_connect_api() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new();
         harness.send_connect_packet();
         harness.validate_challenge();
         let challenge = harness.read_challenge();
@@ -735,9 +1572,38 @@ mod test {
         harness.validate_response();
     }
 
+    #[test]
+    fn test_client_user_data() {
+        let mut harness = TestHarness::<UdpSocket>::new();
+
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        for i in 0..user_data.len() {
+            user_data[i] = i as u8;
+        }
+        harness.replace_connect_token_user_data(&user_data);
+
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        assert!(harness.server.client_user_data(CLIENT_ID).is_none());
+
+        let challenge = harness.read_challenge();
+        harness.send_response(challenge);
+        harness.validate_response();
+
+        match harness.server.client_user_data(CLIENT_ID) {
+            Some(data) => for i in 0..user_data.len() {
+                assert_eq!(data[i], user_data[i]);
+            },
+            None => assert!(false)
+        }
+
+        harness.server.disconnect_client(CLIENT_ID).unwrap();
+        assert!(harness.server.client_user_data(CLIENT_ID).is_none());
+    }
+
     #[test]
     fn test_connect_bad_host() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new();
         let port = harness.server.get_local_addr().unwrap().port();
         harness.replace_connect_token(format!("0.0.0.0:{}", port).as_str(), None);
         harness.send_connect_packet();
@@ -750,9 +1616,43 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_public_addr() {
+        let mut harness = TestHarness::<UdpSocket>::new();
+        harness.server = Server::new_with_public_addrs("127.0.0.1:0", "10.1.2.3:40000", MAX_CLIENTS, PROTOCOL_ID, &harness.private_key).unwrap();
+        harness.server.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
+
+        harness.replace_connect_token("10.1.2.3:40000", None);
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        harness.read_challenge();
+
+        //Bind address is no longer valid once a public address is set
+        let local_addr = harness.server.get_local_addr().unwrap().to_string();
+        harness.replace_connect_token(local_addr.as_str(), None);
+        harness.send_connect_packet();
+
+        let mut data = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        harness.server.update(0.0).unwrap();
+        match harness.server.next_event(&mut data) {
+            Ok(Some(ServerEvent::RejectedClient)) => {},
+            e => assert!(false, "{:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_bad_local_addr() {
+        let private_key = crypto::generate_key();
+        let no_addrs: &[SocketAddr] = &[];
+
+        assert!(UdpServer::new(no_addrs, MAX_CLIENTS, PROTOCOL_ID, &private_key).is_err());
+        assert!(UdpServer::new("not an address", MAX_CLIENTS, PROTOCOL_ID, &private_key).is_err());
+        assert!(UdpServer::new_with_public_addrs(no_addrs, "10.1.2.3:40000", MAX_CLIENTS, PROTOCOL_ID, &private_key).is_err());
+    }
+
     #[test]
     fn test_connect_bad_key() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new();
         let port = harness.server.get_local_addr().unwrap().port();
         harness.replace_connect_token(format!("127.0.0.1:{}", port).as_str(), Some(&crypto::generate_key()));
         harness.send_connect_packet();
@@ -765,9 +1665,36 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_connect_token_reuse() {
+        let mut harness = TestHarness::<UdpSocket>::new();
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        harness.read_challenge();
+
+        //Same token from a different address should be ignored
+        let (len, packet) = harness.generate_connect_packet();
+        let other_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other_socket.send_to(&packet[..len], &harness.server.get_local_addr().unwrap()).unwrap();
+
+        let mut data = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        harness.server.update(0.0).unwrap();
+        match harness.server.next_event(&mut data) {
+            Ok(Some(ServerEvent::RejectedClient)) => {},
+            e => assert!(false, "{:?}", e)
+        }
+
+        //Original address can keep retrying
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        let challenge = harness.read_challenge();
+        harness.send_response(challenge);
+        harness.validate_response();
+    }
+
     #[test]
     fn test_replay_protection() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new();
         harness.send_connect_packet();
         harness.validate_challenge();
         let challenge = harness.read_challenge();
@@ -792,9 +1719,50 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_disconnect_client() {
+        let mut harness = TestHarness::<UdpSocket>::new();
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        let challenge = harness.read_challenge();
+        harness.send_response(challenge);
+        harness.validate_response();
+
+        harness.server.disconnect_client(CLIENT_ID).unwrap();
+
+        for _ in 0..NETCODE_NUM_DISCONNECT_PACKETS {
+            let mut data = [0; NETCODE_MAX_PACKET_SIZE];
+            let (read, _) = harness.socket.recv_from(&mut data).unwrap();
+
+            let mut packet_data = [0; NETCODE_MAX_PAYLOAD_SIZE];
+            match packet::decode(&data[..read], PROTOCOL_ID, Some(&harness.connect_token.server_to_client_key), &mut packet_data) {
+                Ok((_, Packet::Disconnect)) => (),
+                Ok((_, p)) => assert!(false, "unexpected packet type {}", p.get_type_id()),
+                Err(o) => assert!(false, "unexpected {:?}", o)
+            }
+        }
+
+        let mut scratch = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        harness.server.update(0.0).unwrap();
+        match harness.server.next_event(&mut scratch) {
+            Ok(Some(ServerEvent::ClientDisconnect(cid))) => assert_eq!(cid, CLIENT_ID),
+            o => assert!(false, "unexpected {:?}", o)
+        }
+
+        match harness.server.send(CLIENT_ID, &scratch[..1]) {
+            Err(SendError::InvalidClientId) => (),
+            o => assert!(false, "unexpected {:?}", o)
+        }
+
+        match harness.server.disconnect_client(CLIENT_ID) {
+            Err(SendError::InvalidClientId) => (),
+            o => assert!(false, "unexpected {:?}", o)
+        }
+    }
+
     #[test]
     fn test_payload() {
-        let mut harness = TestHarness::<UdpSocket,()>::new(None);
+        let mut harness = TestHarness::<UdpSocket>::new();
         harness.send_connect_packet();
         harness.validate_challenge();
         let challenge = harness.read_challenge();
@@ -808,13 +1776,68 @@ mod test {
             }
 
             harness.send_payload(&data[..s]);
-            harness.validate_recv_payload(&data[..s]);
+            let sequence = harness.validate_recv_payload(&data[..s]);
+            assert_eq!(sequence, harness.next_sequence);
 
             harness.server.send(CLIENT_ID, &data[..s]).unwrap();
             harness.validate_send_payload(&data[..s]);
         }
     }
 
+    #[test]
+    fn test_unconfirmed_keep_alive() {
+        let mut harness = TestHarness::<UdpSocket>::new();
+        harness.send_connect_packet();
+        harness.validate_challenge();
+        let challenge = harness.read_challenge();
+        harness.send_response(challenge);
+        harness.validate_response();
+
+        let data = [1, 2, 3, 4];
+        let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+        let mut sender = harness.server.sender().unwrap();
+
+        //Every payload gets a keep alive until the client confirms, the reported sequence is the payload's
+        for i in 0..2 {
+            let sequence = harness.server.next_packet_sequence(CLIENT_ID).unwrap();
+            assert_eq!(sender.next_packet_sequence(CLIENT_ID), Some(sequence));
+
+            if i == 0 {
+                harness.server.send(CLIENT_ID, &data).unwrap();
+            } else {
+                sender.send(CLIENT_ID, &data).unwrap();
+            }
+
+            match harness.read_packet(&mut payload) {
+                (_, Packet::KeepAlive(_)) => (),
+                (_, p) => assert!(false, "{:?}", p.get_type_id())
+            }
+            match harness.read_packet(&mut payload) {
+                (s, Packet::Payload(len)) => {
+                    assert_eq!(s, sequence);
+                    assert_eq!(len, data.len());
+                },
+                (_, p) => assert!(false, "{:?}", p.get_type_id())
+            }
+        }
+
+        harness.send_payload(&data);
+        harness.validate_recv_payload(&data);
+
+        let sequence = harness.server.next_packet_sequence(CLIENT_ID).unwrap();
+        assert_eq!(sender.next_packet_sequence(CLIENT_ID), Some(sequence));
+
+        harness.server.send(CLIENT_ID, &data).unwrap();
+        match harness.read_packet(&mut payload) {
+            (s, Packet::Payload(len)) => {
+                assert_eq!(s, sequence);
+                assert_eq!(len, data.len());
+            },
+            (_, p) => assert!(false, "{:?}", p.get_type_id())
+        }
+    }
+
+    #[cfg(feature = "capi-tests")]
     #[test]
     fn test_capi_payload() {
         #[allow(unused_variables)]
@@ -822,15 +1845,17 @@ mod test {
 
         use capi::*;
         use std::ffi::CString;
+        use std::str::FromStr;
 
-        let mut harness = TestHarness::<SimulatedSocket, SimulatorRef>::new(Some(1235));
+        let sim = Simulator::new_ref();
+        let server_addr = SocketAddr::from_str("127.0.0.1:1235").unwrap();
+        let mut harness = TestHarness::with_transports(SimulatedSocket::new(&server_addr, &sim), SimulatedSocket::new(&server_addr, &sim));
 
         unsafe {
             //Establish connection
             netcode_init();
 
             let addr = CString::new("127.0.0.1:1234").unwrap();
-            let sim = harness.get_socket_state().clone();
             let client = netcode_client_create_internal(::std::mem::transmute(addr.as_ptr()), 0.0, sim.borrow_mut().sim);
 
             let mut connect_token = vec!();
@@ -904,6 +1929,7 @@ mod test {
         }
     }
 
+    #[cfg(feature = "capi-tests")]
     #[test]
     fn test_capi_connect() {
         #[allow(unused_variables)]
@@ -911,14 +1937,16 @@ mod test {
 
         use capi::*;
         use std::ffi::CString;
+        use std::str::FromStr;
 
-        let mut harness = TestHarness::<SimulatedSocket, SimulatorRef>::new(Some(1235));
+        let sim = Simulator::new_ref();
+        let server_addr = SocketAddr::from_str("127.0.0.1:1235").unwrap();
+        let mut harness = TestHarness::with_transports(SimulatedSocket::new(&server_addr, &sim), SimulatedSocket::new(&server_addr, &sim));
 
         unsafe {
             netcode_init();
 
             let addr = CString::new("127.0.0.1:1234").unwrap();
-            let sim = harness.get_socket_state().clone();
             let client = netcode_client_create_internal(::std::mem::transmute(addr.as_ptr()), 0.0, sim.borrow_mut().sim);
 
             let mut connect_token = vec!();
@@ -0,0 +1,154 @@
+//! Cloneable handle for sending to a server's clients from other threads.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::io;
+
+use common::*;
+use error::SendError;
+use packet::{self, Packet, KeepAlivePacket};
+use socket::{Transport, CloneTransport};
+use loopback::LoopbackEndpoint;
+use server::ClientId;
+use server::connection::Connection;
+
+/// What a sender needs to know about a connected client, kept up to date by the server as clients come and go.
+pub struct SendSlot {
+    pub client_idx: usize,
+    pub socket_idx: usize,
+    pub addr: SocketAddr,
+    pub send_key: [u8; NETCODE_KEY_BYTES],
+    /// Same counter the server's channel uses.
+    pub sequence: Arc<AtomicU64>,
+    /// Set once the server has heard from the client, until then payloads are preceded by a keep alive.
+    pub confirmed: AtomicBool,
+    /// Server time a sender last sent to this client as `f64` bits, the server counts it towards keep alives.
+    pub last_sent: AtomicU64,
+    pub loopback: Option<LoopbackEndpoint>
+}
+
+impl SendSlot {
+    pub fn new(client_idx: usize, client: &Connection) -> SendSlot {
+        SendSlot {
+            client_idx: client_idx,
+            socket_idx: client.socket_idx,
+            addr: *client.channel.get_addr(),
+            send_key: *client.channel.get_send_key(),
+            sequence: client.channel.shared_sequence(),
+            confirmed: AtomicBool::new(client.confirmed),
+            last_sent: AtomicU64::new(0.0f64.to_bits()),
+            loopback: client.loopback.clone()
+        }
+    }
+}
+
+pub type SendSlots = Arc<RwLock<HashMap<ClientId, Arc<SendSlot>>>>;
+
+/// Server time as `f64` bits, set by `Server::update(..)` so senders can tell the server when they last sent.
+pub type SendClock = Arc<AtomicU64>;
+
+/// Sends payloads to a `Server`'s clients without needing `&mut Server`, created with `Server::sender()`.
+///
+/// Each sender has its own handles to the server's sockets, use `try_clone()` to get another one for a different thread. Packets are
+/// encrypted on the calling thread with sequence numbers drawn from the same counters the server uses. A client that disconnects
+/// while a send is in progress may still be sent that one packet.
+pub struct ServerSender<T> where T: Transport {
+    sockets: Vec<T>,
+    slots: SendSlots,
+    clock: SendClock,
+    protocol_id: u64,
+    max_clients: usize
+}
+
+impl<T> ServerSender<T> where T: CloneTransport {
+    /// Creates another sender for the same server with its own copies of the sockets.
+    pub fn try_clone(&self) -> Result<ServerSender<T>, io::Error> {
+        let sockets = self.sockets.iter().map(|s| s.try_clone()).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ServerSender::new(sockets, self.slots.clone(), self.clock.clone(), self.protocol_id, self.max_clients))
+    }
+}
+
+impl<T> ServerSender<T> where T: Transport {
+    pub(crate) fn new(sockets: Vec<T>, slots: SendSlots, clock: SendClock, protocol_id: u64, max_clients: usize) -> ServerSender<T> {
+        ServerSender {
+            sockets: sockets,
+            slots: slots,
+            clock: clock,
+            protocol_id: protocol_id,
+            max_clients: max_clients
+        }
+    }
+
+    /// Sends a packet to `client_id` specified.
+    pub fn send(&mut self, client_id: ClientId, payload: &[u8]) -> Result<usize, SendError> {
+        if payload.len() == 0 || payload.len() > NETCODE_MAX_PAYLOAD_SIZE {
+            return Err(SendError::PacketSize)
+        }
+
+        let slot = match self.find_slot(client_id) {
+            Some(slot) => slot,
+            None => {
+                trace!("Unable to send packet, invalid client id {}", client_id);
+                return Err(SendError::InvalidClientId)
+            }
+        };
+
+        if let Some(ref loopback) = slot.loopback {
+            return loopback.send(payload).map(|_| payload.len())
+        }
+
+        //Same as Server::send(), keep completing the connection until the client talks to us
+        if !slot.confirmed.load(Ordering::Relaxed) {
+            let keep_alive = KeepAlivePacket {
+                client_idx: slot.client_idx as i32,
+                max_clients: self.max_clients as i32
+            };
+
+            self.send_packet(&slot, &Packet::KeepAlive(keep_alive), None)?;
+        }
+
+        self.send_packet(&slot, &Packet::Payload(payload.len()), Some(payload))
+    }
+
+    /// Gets the sequence number that will be used for the next payload sent to `client_id`, `None` if the client isn't connected.
+    /// Accounts for the keep alive sent ahead of payloads to unconfirmed clients, see `Server::next_packet_sequence(..)`.
+    pub fn next_packet_sequence(&self, client_id: ClientId) -> Option<u64> {
+        self.find_slot(client_id).map(|slot| match slot.loopback {
+            Some(ref loopback) => loopback.get_next_sequence(),
+            None if slot.confirmed.load(Ordering::Relaxed) => slot.sequence.load(Ordering::Relaxed),
+            None => slot.sequence.load(Ordering::Relaxed) + 1
+        })
+    }
+
+    /// Checks if `client_id` is currently connected to the server.
+    pub fn is_connected(&self, client_id: ClientId) -> bool {
+        self.find_slot(client_id).is_some()
+    }
+
+    /// Sends any packets held back by a batching transport, see `Server::flush()`.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        for socket in self.sockets.iter_mut() {
+            socket.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn find_slot(&self, client_id: ClientId) -> Option<Arc<SendSlot>> {
+        self.slots.read().unwrap().get(&client_id).cloned()
+    }
+
+    fn send_packet(&mut self, slot: &SendSlot, packet: &Packet, payload: Option<&[u8]>) -> Result<usize, SendError> {
+        let mut scratch = [0; NETCODE_MAX_PACKET_SIZE];
+        let sequence = slot.sequence.fetch_add(1, Ordering::Relaxed);
+        let len = packet::encode(&mut scratch, self.protocol_id, packet, Some((sequence, &slot.send_key)), payload)?;
+
+        self.sockets[slot.socket_idx].send_to(&scratch[..len], &slot.addr)?;
+        slot.last_sent.store(self.clock.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        Ok(len)
+    }
+}
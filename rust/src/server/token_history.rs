@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+
+use common::*;
+
+/// Number of connect tokens remembered per client slot.
+const TOKEN_ENTRIES_PER_CLIENT: usize = 8;
+
+#[derive(Clone)]
+struct TokenEntry {
+    time: f64,
+    mac: [u8; NETCODE_MAC_BYTES],
+    addr: Option<SocketAddr>
+}
+
+/// Bounded history of recently used connect tokens and the address that first used them.
+pub struct TokenHistory {
+    entries: Vec<TokenEntry>
+}
+
+impl TokenHistory {
+    pub fn new(max_clients: usize) -> TokenHistory {
+        let entry = TokenEntry {
+            time: -1000.0,
+            mac: [0; NETCODE_MAC_BYTES],
+            addr: None
+        };
+
+        TokenHistory {
+            entries: vec!(entry; max_clients * TOKEN_ENTRIES_PER_CLIENT)
+        }
+    }
+
+    /// Records use of the token identified by `mac` from `addr`, replacing the oldest entry if this token is new.
+    /// Returns false if the token was already used from a different address.
+    pub fn find_or_add(&mut self, mac: &[u8; NETCODE_MAC_BYTES], addr: &SocketAddr, time: f64) -> bool {
+        //Always scan every entry so we take the same time regardless of a match
+        let mut matching = None;
+        let mut oldest = 0;
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.mac == *mac {
+                matching = Some(i);
+            }
+
+            if entry.time < self.entries[oldest].time {
+                oldest = i;
+            }
+        }
+
+        match matching {
+            Some(idx) => self.entries[idx].addr == Some(*addr),
+            None => {
+                self.entries[oldest] = TokenEntry {
+                    time: time,
+                    mac: *mac,
+                    addr: Some(*addr)
+                };
+
+                true
+            }
+        }
+    }
+}
+
+#[test]
+fn test_token_history() {
+    use std::str::FromStr;
+
+    let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+    let other_addr = SocketAddr::from_str("127.0.0.1:1001").unwrap();
+
+    let mut history = TokenHistory::new(1);
+
+    let mut mac = [0xFF; NETCODE_MAC_BYTES];
+    assert!(history.find_or_add(&mac, &addr, 0.0));
+    assert!(history.find_or_add(&mac, &addr, 1.0));
+    assert!(!history.find_or_add(&mac, &other_addr, 1.0));
+
+    //Fill history so original token is evicted
+    for i in 0..TOKEN_ENTRIES_PER_CLIENT {
+        mac[0] = i as u8;
+        assert!(history.find_or_add(&mac, &other_addr, 2.0 + i as f64));
+    }
+
+    mac[0] = 0xFF;
+    assert!(history.find_or_add(&mac, &other_addr, 20.0));
+}
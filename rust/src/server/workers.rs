@@ -0,0 +1,360 @@
+//! Worker threads that decrypt and encrypt packets for the server, sharded by client slot.
+//!
+//! Every packet for a slot goes through the same worker so a client's packets come back in the order they were queued.
+//! Replay protection and connection state stay on the thread calling `next_event(..)`, workers only see keys and bytes.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+
+use common::*;
+use packet::{self, Packet, PacketError};
+use server::ClientId;
+
+/// Packet from a connected client waiting to be decrypted.
+pub struct DecodeJob {
+    pub client_idx: usize,
+    /// Client and address the packet was read for, the slot may have been reused by the time it's decrypted.
+    pub client_id: ClientId,
+    pub addr: SocketAddr,
+    pub key: [u8; NETCODE_KEY_BYTES],
+    pub data: Vec<u8>
+}
+
+/// Decrypted packet, `payload` holds the contents of a `Packet::Payload`.
+pub struct Decoded {
+    pub client_idx: usize,
+    pub client_id: ClientId,
+    pub addr: SocketAddr,
+    pub result: Result<(u64, Packet), PacketError>,
+    pub payload: Vec<u8>
+}
+
+/// Payload waiting to be encrypted, the sequence number is reserved when it's queued.
+pub struct EncodeJob {
+    pub socket_idx: usize,
+    pub addr: SocketAddr,
+    pub sequence: u64,
+    pub key: [u8; NETCODE_KEY_BYTES],
+    pub payload: Vec<u8>
+}
+
+/// Encrypted packet ready to be sent from `socket_idx`.
+pub struct Encoded {
+    pub socket_idx: usize,
+    pub addr: SocketAddr,
+    pub result: Result<Vec<u8>, PacketError>
+}
+
+enum Job {
+    Decode(DecodeJob),
+    Encode(EncodeJob)
+}
+
+struct Worker {
+    jobs: Sender<Job>,
+    thread: JoinHandle<()>
+}
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    decoded: Receiver<Decoded>,
+    encoded: Receiver<Encoded>,
+    decodes_in_flight: usize,
+    encodes_in_flight: usize
+}
+
+impl WorkerPool {
+    pub fn new(count: usize, protocol_id: u64) -> Result<WorkerPool, io::Error> {
+        let (decoded_send, decoded) = mpsc::channel();
+        let (encoded_send, encoded) = mpsc::channel();
+
+        let mut workers = Vec::with_capacity(count);
+        for i in 0..count {
+            let (jobs, jobs_recv) = mpsc::channel();
+            let decoded_send = decoded_send.clone();
+            let encoded_send = encoded_send.clone();
+
+            let thread = thread::Builder::new()
+                .name(format!("netcode-worker-{}", i))
+                .spawn(move || run(protocol_id, jobs_recv, decoded_send, encoded_send))?;
+
+            workers.push(Worker {
+                jobs: jobs,
+                thread: thread
+            });
+        }
+
+        Ok(WorkerPool {
+            workers: workers,
+            decoded: decoded,
+            encoded: encoded,
+            decodes_in_flight: 0,
+            encodes_in_flight: 0
+        })
+    }
+
+    pub fn decode(&mut self, job: DecodeJob) {
+        let shard = job.client_idx % self.workers.len();
+        if self.workers[shard].jobs.send(Job::Decode(job)).is_ok() {
+            self.decodes_in_flight += 1;
+        }
+    }
+
+    pub fn encode(&mut self, client_idx: usize, job: EncodeJob) {
+        let shard = client_idx % self.workers.len();
+        if self.workers[shard].jobs.send(Job::Encode(job)).is_ok() {
+            self.encodes_in_flight += 1;
+        }
+    }
+
+    pub fn decodes_in_flight(&self) -> usize {
+        self.decodes_in_flight
+    }
+
+    /// Waits for the next decrypted packet, `None` once nothing is left in flight.
+    pub fn next_decoded(&mut self) -> Option<Decoded> {
+        if self.decodes_in_flight == 0 {
+            return None
+        }
+
+        self.decoded.recv().ok().map(|decoded| {
+            self.decodes_in_flight -= 1;
+            decoded
+        })
+    }
+
+    /// Takes the next encrypted packet, if `wait` is set this blocks until one is ready or nothing is left in flight.
+    pub fn next_encoded(&mut self, wait: bool) -> Option<Encoded> {
+        if self.encodes_in_flight == 0 {
+            return None
+        }
+
+        let encoded = if wait {
+            self.encoded.recv().ok()
+        } else {
+            self.encoded.try_recv().ok()
+        };
+
+        encoded.map(|encoded| {
+            self.encodes_in_flight -= 1;
+            encoded
+        })
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        //Closing the job queues lets each worker finish what it has and exit
+        let threads = self.workers.drain(..).map(|w| w.thread).collect::<Vec<_>>();
+        for thread in threads {
+            if thread.join().is_err() {
+                info!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+fn run(protocol_id: u64, jobs: Receiver<Job>, decoded: Sender<Decoded>, encoded: Sender<Encoded>) {
+    let mut out = [0; NETCODE_MAX_PAYLOAD_SIZE];
+
+    for job in jobs.iter() {
+        let sent = match job {
+            Job::Decode(job) => {
+                let result = packet::decode(&job.data, protocol_id, Some(&job.key), &mut out);
+
+                //Reuse the packet's buffer for the payload
+                let mut payload = job.data;
+                payload.clear();
+                if let Ok((_, Packet::Payload(len))) = result {
+                    payload.extend_from_slice(&out[..len]);
+                }
+
+                decoded.send(Decoded {
+                    client_idx: job.client_idx,
+                    client_id: job.client_id,
+                    addr: job.addr,
+                    result: result,
+                    payload: payload
+                }).is_ok()
+            },
+            Job::Encode(job) => {
+                let mut data = vec!(0; NETCODE_MAX_PACKET_SIZE);
+                let result = packet::encode(&mut data, protocol_id, &Packet::Payload(job.payload.len()), Some((job.sequence, &job.key)), Some(&job.payload))
+                    .map(|len| {
+                        data.truncate(len);
+                        data
+                    });
+
+                encoded.send(Encoded {
+                    socket_idx: job.socket_idx,
+                    addr: job.addr,
+                    result: result
+                }).is_ok()
+            }
+        };
+
+        //Server has gone away
+        if !sent {
+            break
+        }
+    }
+}
+
+#[test]
+fn test_worker_pool() {
+    use std::str::FromStr;
+    use crypto;
+
+    const PROTOCOL_ID: u64 = 0xFFCC;
+    const NUM_CLIENTS: usize = 8;
+    const PACKETS: u64 = 32;
+
+    let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+    let keys = (0..NUM_CLIENTS).map(|_| crypto::generate_key()).collect::<Vec<_>>();
+    let mut pool = WorkerPool::new(3, PROTOCOL_ID).unwrap();
+
+    for sequence in 0..PACKETS {
+        for idx in 0..NUM_CLIENTS {
+            pool.encode(idx, EncodeJob {
+                socket_idx: idx,
+                addr: addr,
+                sequence: sequence,
+                key: keys[idx],
+                payload: vec!(sequence as u8; idx + 1)
+            });
+        }
+    }
+
+    //Encrypted packets for each client come back in the order they were queued
+    let mut next_sequence = vec!(0; NUM_CLIENTS);
+    while let Some(encoded) = pool.next_encoded(true) {
+        let idx = encoded.socket_idx;
+        let sequence = next_sequence[idx];
+        next_sequence[idx] += 1;
+
+        pool.decode(DecodeJob {
+            client_idx: idx,
+            client_id: idx as ClientId,
+            addr: addr,
+            key: keys[idx],
+            data: encoded.result.unwrap()
+        });
+
+        assert_eq!(sequence, pool.next_decoded().map(|d| d.result.unwrap().0).unwrap());
+    }
+    assert!(next_sequence.iter().all(|&s| s == PACKETS));
+
+    //Wrong key fails to decrypt
+    pool.encode(0, EncodeJob {
+        socket_idx: 0,
+        addr: addr,
+        sequence: 0,
+        key: keys[0],
+        payload: vec!(1, 2, 3)
+    });
+    let data = pool.next_encoded(true).unwrap().result.unwrap();
+
+    for &(key, ok) in [(keys[1], false), (keys[0], true)].iter() {
+        pool.decode(DecodeJob {
+            client_idx: 0,
+            client_id: 0,
+            addr: addr,
+            key: key,
+            data: data.clone()
+        });
+
+        let decoded = pool.next_decoded().unwrap();
+        assert_eq!(decoded.result.is_ok(), ok);
+        if ok {
+            assert_eq!(decoded.payload, vec!(1, 2, 3));
+        }
+    }
+
+    assert!(pool.next_decoded().is_none());
+    assert!(pool.next_encoded(true).is_none());
+}
+
+#[test]
+fn test_worker_threads() {
+    use std::str::FromStr;
+    use client::{Client, ClientEvent, State};
+    use server::{Server, ServerEvent};
+    use simulator::{Simulator, SimulatorConfig};
+    use token::ConnectToken;
+    use crypto;
+
+    const PROTOCOL_ID: u64 = 0xFFCC;
+    const NUM_CLIENTS: usize = 16;
+    const FRAMES: usize = 60;
+
+    //Duplicates arrive after the original so every client's packets should come out in order with copies rejected
+    let mut config = SimulatorConfig::default();
+    config.duplicate_packet_percent = 50.0;
+
+    let sim = Simulator::new(config, 7);
+    let private_key = crypto::generate_key();
+
+    let mut server = Server::with_transport(sim.bind(&SocketAddr::from_str("10.0.0.1:40000").unwrap()).unwrap(), NUM_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+    server.set_worker_threads(4).unwrap();
+    let server_addr = server.get_local_addr().unwrap();
+
+    let mut clients = vec!();
+    for i in 0..NUM_CLIENTS {
+        let token = ConnectToken::generate([server_addr].iter().cloned(), &private_key, 30, i as u64, PROTOCOL_ID, i as u64, None).unwrap();
+        clients.push(Client::with_transport(&token, sim.bind(&SocketAddr::from_str("10.0.1.1:0").unwrap()).unwrap()).unwrap());
+    }
+
+    let dt = 1.0 / 30.0;
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+    let mut server_received = vec!(0; NUM_CLIENTS);
+    let mut client_received = vec!(0; NUM_CLIENTS);
+    let mut replays = 0;
+
+    for frame in 0..FRAMES * 3 {
+        let sending = frame >= FRAMES && frame < FRAMES * 2;
+        if sending {
+            for client in clients.iter_mut() {
+                client.send(&[(frame - FRAMES) as u8; 32]).unwrap();
+            }
+        }
+
+        sim.update(dt);
+
+        server.update(dt).unwrap();
+        while let Some(event) = server.next_event(&mut payload).unwrap() {
+            match event {
+                ServerEvent::Packet(id, len, _) => {
+                    assert_eq!(len, 32);
+                    assert_eq!(payload[0] as usize, server_received[id as usize]);
+                    server_received[id as usize] += 1;
+                    server.send(id, &payload[..len]).unwrap();
+                },
+                ServerEvent::ReplayRejected(_) => replays += 1,
+                ServerEvent::ClientDisconnect(id) => assert!(false, "client {} disconnected", id),
+                _ => ()
+            }
+        }
+        server.flush().unwrap();
+
+        for (idx, client) in clients.iter_mut().enumerate() {
+            client.update(dt).unwrap();
+            while let Some(event) = client.next_event(&mut payload).unwrap() {
+                if let ClientEvent::Packet(len, _) = event {
+                    assert_eq!(len, 32);
+                    assert_eq!(payload[0] as usize, client_received[idx]);
+                    client_received[idx] += 1;
+                }
+            }
+        }
+
+        if frame == FRAMES - 1 {
+            assert!(clients.iter().all(|c| c.get_state() == State::Connected));
+        }
+    }
+
+    assert!(server_received.iter().all(|&r| r == FRAMES), "{:?}", server_received);
+    assert!(client_received.iter().all(|&r| r == FRAMES), "{:?}", client_received);
+    assert!(replays > 0);
+}
@@ -0,0 +1,407 @@
+//! Pure Rust network simulator for running clients and servers in-process without real sockets.
+
+use socket::Transport;
+
+use std::net::SocketAddr;
+use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+/// First port handed out when binding to port 0.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Network conditions applied to every packet sent through a `Simulator`.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    /// Base delay in seconds before a packet is delivered.
+    pub latency: f64,
+    /// Random delay in seconds added or removed from `latency`, packets with different delays arrive out of order.
+    pub jitter: f64,
+    /// Percent chance(0-100) that a packet is dropped.
+    pub packet_loss_percent: f64,
+    /// Percent chance(0-100) that a packet is delivered twice, the copy arrives up to a second later.
+    pub duplicate_packet_percent: f64,
+    /// Percent chance(0-100) that a packet is held back up to a second, letting later packets overtake it.
+    pub reorder_packet_percent: f64
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> SimulatorConfig {
+        SimulatorConfig {
+            latency: 0.0,
+            jitter: 0.0,
+            packet_loss_percent: 0.0,
+            duplicate_packet_percent: 0.0,
+            reorder_packet_percent: 0.0
+        }
+    }
+}
+
+struct PacketEntry {
+    from: SocketAddr,
+    to: SocketAddr,
+    data: Vec<u8>,
+    delivery_time: f64,
+    /// Breaks ties between packets delivered at the same time so runs are deterministic.
+    order: u64
+}
+
+struct SimulatorState {
+    config: SimulatorConfig,
+    time: f64,
+    rng: u64,
+    next_order: u64,
+    next_port: u16,
+    in_flight: Vec<PacketEntry>,
+    inboxes: HashMap<SocketAddr, VecDeque<(SocketAddr, Vec<u8>)>>
+}
+
+impl SimulatorState {
+    //xorshift64*, we need the same sequence for a given seed on every platform
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn random_float(&mut self, min: f64, max: f64) -> f64 {
+        let unit = (self.random() >> 11) as f64 / (1u64 << 53) as f64;
+        min + (max - min) * unit
+    }
+
+    fn queue_packet(&mut self, from: &SocketAddr, to: &SocketAddr, data: &[u8], delay: f64) {
+        let order = self.next_order;
+        self.next_order += 1;
+
+        self.in_flight.push(PacketEntry {
+            from: from.clone(),
+            to: to.clone(),
+            data: data.to_vec(),
+            delivery_time: self.time + delay.max(0.0),
+            order: order
+        });
+    }
+
+    fn send(&mut self, from: &SocketAddr, to: &SocketAddr, data: &[u8]) {
+        if self.random_float(0.0, 100.0) < self.config.packet_loss_percent {
+            trace!("Simulator dropped packet from {:?} to {:?}", from, to);
+            return
+        }
+
+        let mut delay = self.config.latency;
+        if self.config.jitter > 0.0 {
+            let jitter = self.config.jitter;
+            delay += self.random_float(-jitter, jitter);
+        }
+
+        if self.random_float(0.0, 100.0) < self.config.reorder_packet_percent {
+            delay += self.random_float(0.0, 1.0);
+        }
+
+        self.queue_packet(from, to, data, delay);
+
+        if self.random_float(0.0, 100.0) < self.config.duplicate_packet_percent {
+            let duplicate_delay = delay + self.random_float(0.0, 1.0);
+            self.queue_packet(from, to, data, duplicate_delay);
+        }
+    }
+
+    fn update(&mut self, elapsed: f64) {
+        self.time += elapsed;
+
+        let time = self.time;
+        let (mut ready, in_flight): (Vec<_>, Vec<_>) = self.in_flight.drain(..).partition(|p| p.delivery_time <= time);
+        self.in_flight = in_flight;
+
+        ready.sort_by(|a, b| a.delivery_time.partial_cmp(&b.delivery_time).unwrap().then(a.order.cmp(&b.order)));
+
+        for packet in ready.into_iter() {
+            match self.inboxes.get_mut(&packet.to) {
+                Some(inbox) => inbox.push_back((packet.from, packet.data)),
+                None => trace!("Simulator dropped packet to unbound address {:?}", packet.to)
+            }
+        }
+    }
+}
+
+/// In-process network that `SimulatedTransport`s send packets through.
+///
+/// Packets are only delivered when `update(..)` is called, so advance it alongside `Server::update(..)` and `Client::update(..)`
+/// with the same elapsed time. A simulator created with the same seed and driven the same way will always deliver the same packets.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Rc<RefCell<SimulatorState>>
+}
+
+impl Simulator {
+    /// Constructs a new simulator with network `config` and `seed` for the random number generator.
+    pub fn new(config: SimulatorConfig, seed: u64) -> Simulator {
+        Simulator {
+            state: Rc::new(RefCell::new(SimulatorState {
+                config: config,
+                time: 0.0,
+                //xorshift gets stuck on zero
+                rng: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed },
+                next_order: 0,
+                next_port: EPHEMERAL_PORT_START,
+                in_flight: vec!(),
+                inboxes: HashMap::new()
+            }))
+        }
+    }
+
+    /// Replaces the network conditions, packets already in flight keep their delivery time.
+    pub fn set_config(&self, config: SimulatorConfig) {
+        self.state.borrow_mut().config = config;
+    }
+
+    /// Creates a transport that receives packets sent to `addr`, port 0 is replaced with an unused port.
+    pub fn bind(&self, addr: &SocketAddr) -> Result<SimulatedTransport, io::Error> {
+        let mut state = self.state.borrow_mut();
+        let mut addr = addr.clone();
+
+        if addr.port() == 0 {
+            loop {
+                let port = state.next_port;
+                state.next_port = state.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+
+                addr.set_port(port);
+                if !state.inboxes.contains_key(&addr) {
+                    break
+                }
+            }
+        }
+
+        if state.inboxes.contains_key(&addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "Address already bound in simulator"))
+        }
+
+        state.inboxes.insert(addr.clone(), VecDeque::new());
+
+        Ok(SimulatedTransport {
+            addr: addr,
+            state: self.state.clone()
+        })
+    }
+
+    /// Advances simulator time by `elapsed` seconds and delivers any packets that have arrived.
+    pub fn update(&self, elapsed: f64) {
+        self.state.borrow_mut().update(elapsed);
+    }
+
+    /// Drops every packet that is in flight or waiting to be received.
+    pub fn discard_packets(&self) {
+        let mut state = self.state.borrow_mut();
+        state.in_flight.clear();
+
+        for (_, inbox) in state.inboxes.iter_mut() {
+            inbox.clear();
+        }
+    }
+}
+
+/// Transport bound to an address on a `Simulator`, see [Transport](trait.Transport.html).
+pub struct SimulatedTransport {
+    addr: SocketAddr,
+    state: Rc<RefCell<SimulatorState>>
+}
+
+impl Drop for SimulatedTransport {
+    fn drop(&mut self) {
+        self.state.borrow_mut().inboxes.remove(&self.addr);
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.addr.clone())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        let mut state = self.state.borrow_mut();
+        let packet = state.inboxes.get_mut(&self.addr).and_then(|inbox| inbox.pop_front());
+
+        match packet {
+            Some((from, data)) => {
+                //Match UdpSocket and truncate anything that doesn't fit
+                let len = ::std::cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+
+                Ok((len, from))
+            },
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No packets available"))
+        }
+    }
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Result<usize, io::Error> {
+        self.state.borrow_mut().send(&self.addr, addr, buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    use std::str::FromStr;
+    SocketAddr::from_str(s).unwrap()
+}
+
+#[cfg(test)]
+fn recv_all(transport: &mut SimulatedTransport) -> Vec<u8> {
+    let mut received = vec!();
+    let mut buf = [0; 1];
+    while let Ok((_, _)) = transport.recv_from(&mut buf) {
+        received.push(buf[0]);
+    }
+
+    received
+}
+
+#[test]
+fn test_latency() {
+    let mut config = SimulatorConfig::default();
+    config.latency = 0.25;
+
+    let sim = Simulator::new(config, 1);
+    let mut a = sim.bind(&addr("127.0.0.1:0")).unwrap();
+    let mut b = sim.bind(&addr("127.0.0.1:0")).unwrap();
+    assert!(a.local_addr().unwrap() != b.local_addr().unwrap());
+
+    let b_addr = b.local_addr().unwrap();
+    a.send_to(&[1], &b_addr).unwrap();
+
+    sim.update(0.2);
+    assert_eq!(recv_all(&mut b), vec!());
+
+    sim.update(0.1);
+    let mut buf = [0; 1];
+    assert_eq!(b.recv_from(&mut buf).unwrap(), (1, a.local_addr().unwrap()));
+}
+
+#[test]
+fn test_loss_and_duplicates() {
+    let mut config = SimulatorConfig::default();
+    config.packet_loss_percent = 100.0;
+
+    let sim = Simulator::new(config.clone(), 1);
+    let mut a = sim.bind(&addr("127.0.0.1:1000")).unwrap();
+    let mut b = sim.bind(&addr("127.0.0.1:1001")).unwrap();
+    assert!(sim.bind(&addr("127.0.0.1:1001")).is_err());
+
+    a.send_to(&[1], &addr("127.0.0.1:1001")).unwrap();
+    sim.update(1.0);
+    assert_eq!(recv_all(&mut b), vec!());
+
+    config.packet_loss_percent = 0.0;
+    config.duplicate_packet_percent = 100.0;
+    sim.set_config(config);
+
+    a.send_to(&[2], &addr("127.0.0.1:1001")).unwrap();
+    sim.update(1.0);
+    assert_eq!(recv_all(&mut b), vec!(2, 2));
+}
+
+#[test]
+fn test_deterministic() {
+    let run = |seed| {
+        let config = SimulatorConfig {
+            latency: 0.05,
+            jitter: 0.05,
+            packet_loss_percent: 20.0,
+            duplicate_packet_percent: 10.0,
+            reorder_packet_percent: 10.0
+        };
+
+        let sim = Simulator::new(config, seed);
+        let mut a = sim.bind(&addr("127.0.0.1:1000")).unwrap();
+        let mut b = sim.bind(&addr("127.0.0.1:1001")).unwrap();
+
+        let mut received = vec!();
+        for i in 0..200 {
+            a.send_to(&[i as u8], &addr("127.0.0.1:1001")).unwrap();
+            sim.update(1.0 / 60.0);
+            received.extend(recv_all(&mut b));
+        }
+
+        sim.update(2.0);
+        received.extend(recv_all(&mut b));
+
+        received
+    };
+
+    let first = run(1234);
+    assert_eq!(first, run(1234));
+
+    //Make sure the conditions actually did something
+    let mut sorted = first.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert!(first.len() < 200 + 200 / 10 * 2);
+    assert!(sorted.len() < 200);
+    assert!(first != sorted);
+}
+
+#[test]
+fn test_client_server() {
+    use common::*;
+    use client::{Client, ClientEvent, State};
+    use server::{Server, ServerEvent};
+    use token::ConnectToken;
+    use crypto;
+
+    const PROTOCOL_ID: u64 = 0xFFCC;
+    const NUM_CLIENTS: usize = 16;
+
+    let config = SimulatorConfig {
+        latency: 0.05,
+        jitter: 0.02,
+        packet_loss_percent: 5.0,
+        duplicate_packet_percent: 5.0,
+        reorder_packet_percent: 5.0
+    };
+
+    let sim = Simulator::new(config, 42);
+    let private_key = crypto::generate_key();
+
+    let mut server = Server::with_transport(sim.bind(&addr("10.0.0.1:40000")).unwrap(), NUM_CLIENTS, PROTOCOL_ID, &private_key).unwrap();
+    let server_addr = server.get_local_addr().unwrap();
+
+    let mut clients = vec!();
+    for i in 0..NUM_CLIENTS {
+        let token = ConnectToken::generate([server_addr].iter().cloned(), &private_key, 30, i as u64, PROTOCOL_ID, i as u64, None).unwrap();
+        clients.push(Client::with_transport(&token, sim.bind(&addr("10.0.1.1:0")).unwrap()).unwrap());
+    }
+
+    let mut connected = 0;
+    let mut payload = [0; NETCODE_MAX_PAYLOAD_SIZE];
+    for _ in 0..300 {
+        let dt = 1.0 / 30.0;
+        sim.update(dt);
+
+        server.update(dt).unwrap();
+        while let Some(event) = server.next_event(&mut payload).unwrap() {
+            match event {
+                ServerEvent::ClientConnect(_) => connected += 1,
+                ServerEvent::ClientDisconnect(id) => assert!(false, "client {} disconnected", id),
+                _ => ()
+            }
+        }
+
+        for client in clients.iter_mut() {
+            client.update(dt).unwrap();
+            while let Some(event) = client.next_event(&mut payload).unwrap() {
+                if let ClientEvent::NewState(state) = event {
+                    assert!(state == State::SendingConnectionRequest || state == State::SendingConnectionResponse || state == State::Connected, "{:?}", state);
+                }
+            }
+        }
+
+        if connected == NUM_CLIENTS && clients.iter().all(|c| c.get_state() == State::Connected) {
+            break
+        }
+    }
+
+    assert_eq!(connected, NUM_CLIENTS);
+    assert!(clients.iter().all(|c| c.get_state() == State::Connected));
+}
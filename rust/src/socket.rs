@@ -2,27 +2,56 @@ use std::net::{SocketAddr, UdpSocket};
 use std::io;
 use std::time::Duration;
 
-pub trait SocketProvider<I,S> {
-    fn new_state() -> S;
-    fn bind(addr: &SocketAddr, state: &mut S) -> Result<I, io::Error>;
+/// Datagram transport that `Server` and `Client` send and receive packets over.
+///
+/// `UdpSocket` is the default, implement this to run netcode.io over relays, tunnels or an in-process transport.
+/// Packets are already encrypted by the time they reach the transport so it only needs to move bytes between addresses.
+pub trait Transport {
+    /// Address this transport receives on, servers validate connect tokens against it by default.
     fn local_addr(&self) -> Result<SocketAddr, io::Error>;
-    fn set_recv_timeout(&mut self, duration: Option<Duration>) -> Result<(), io::Error>;
+    /// Receives a single datagram. Must return an error of kind `io::ErrorKind::WouldBlock` instead of blocking when no data is ready,
+    /// unless a timeout was set with `set_recv_timeout(..)`.
     fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error>;
+    /// Sends a single datagram to `addr`.
     fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Result<usize, io::Error>;
+    /// Sends any datagrams a batching transport has held back, called from `Server::flush()` and `Client::flush()`.
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+    /// Makes `recv_from(..)` wait up to `duration` for data, `None` restores non-blocking behavior. Transports that can't block may ignore this.
+    fn set_recv_timeout(&mut self, _duration: Option<Duration>) -> Result<(), io::Error> {
+        Ok(())
+    }
 }
 
-impl SocketProvider<UdpSocket,()> for UdpSocket {
-    fn new_state() -> () {
-        ()
-    }
+/// Transport that can be created from just a local address, needed by the constructors that take an address.
+pub trait BindTransport: Transport + Sized {
+    /// Creates a non-blocking transport bound to `addr`.
+    fn bind(addr: &SocketAddr) -> Result<Self, io::Error>;
+}
 
-    fn bind(addr: &SocketAddr, _state: &mut ()) -> Result<UdpSocket, io::Error> {
+/// Transport that can be duplicated so another thread can send on it, needed by `Server::sender()`.
+pub trait CloneTransport: Transport + Sized {
+    /// Creates another handle to the same underlying transport, only `send_to(..)` is called on the copy.
+    fn try_clone(&self) -> Result<Self, io::Error>;
+}
+
+impl BindTransport for UdpSocket {
+    fn bind(addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
         let socket = UdpSocket::bind(addr)?;
         socket.set_nonblocking(true)?;
 
         Ok(socket)
     }
+}
 
+impl CloneTransport for UdpSocket {
+    fn try_clone(&self) -> Result<UdpSocket, io::Error> {
+        UdpSocket::try_clone(self)
+    }
+}
+
+impl Transport for UdpSocket {
     fn local_addr(&self) -> Result<SocketAddr, io::Error> {
         UdpSocket::local_addr(self)
     }
@@ -48,7 +77,7 @@ impl SocketProvider<UdpSocket,()> for UdpSocket {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "capi-tests"))]
 pub mod capi_simulator {
     use super::*;
     use capi::*;
@@ -102,28 +131,28 @@ pub mod capi_simulator {
         }
     }
 
-    impl SocketProvider<SimulatedSocket, SimulatorRef> for SimulatedSocket {
-        fn new_state() -> Rc<RefCell<Simulator>> {
+    impl Simulator {
+        pub fn new_ref() -> SimulatorRef {
             Rc::new(RefCell::new(Simulator {
                 sim: unsafe { netcode_network_simulator_create() }
             }))
         }
+    }
 
-        fn bind(addr: &SocketAddr, state: &mut SimulatorRef) -> Result<SimulatedSocket, io::Error> {
-            Ok(SimulatedSocket {
+    impl SimulatedSocket {
+        pub fn new(addr: &SocketAddr, sim: &SimulatorRef) -> SimulatedSocket {
+            SimulatedSocket {
                 local_addr: addr.clone(),
-                sim: Rc::downgrade(state)
-            })
+                sim: Rc::downgrade(sim)
+            }
         }
+    }
 
+    impl Transport for SimulatedSocket {
         fn local_addr(&self) -> Result<SocketAddr, io::Error> {
             Ok(self.local_addr)
         }
 
-        fn set_recv_timeout(&mut self, _duration: Option<Duration>) -> Result<(), io::Error> {
-            Ok(())
-        }
-
         fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
             unsafe {
                 let mut packet = [::std::ptr::null_mut(); 1];
@@ -107,7 +107,13 @@ pub struct PrivateData {
     /// Private key for server -> client communcation.
     pub server_to_client_key: [u8; NETCODE_KEY_BYTES],
     /// Server-specific user data.
-    pub user_data: [u8; NETCODE_USER_DATA_BYTES]
+    pub user_data: [u8; NETCODE_USER_DATA_BYTES],
+    /// Time in seconds connection should wait before disconnecting.
+    ///
+    /// This is an extension to the netcode.io 1.00 layout, which only carries the timeout in the public part of the token.
+    /// It's written after `user_data` in what 1.00 leaves as zero padding, so other implementations ignore it and their
+    /// tokens read back as zero which falls back to `NETCODE_TIMEOUT_SECONDS`.
+    pub timeout_sec: u32
 }
 
 #[derive(Clone,Debug)]
@@ -167,14 +173,41 @@ impl ConnectToken {
                        user_data: Option<&[u8; 256]>)
                        -> Result<ConnectToken, GenerateError>
                           where H: ExactSizeIterator<Item=SocketAddr> {
+        Self::generate_with_timeout(hosts, private_key, expire_sec, NETCODE_TIMEOUT_SECONDS, sequence, protocol, client_id, user_data)
+    }
+
+    /// Generates a new connection token with a custom connection timeout.
+    ///
+    /// The timeout is also stored in the private data so the server can use it, see `PrivateData::timeout_sec`. Servers built
+    /// on other netcode.io implementations will use their default timeout instead.
+    /// # Arguments
+    /// `timeout_sec`: How long client and server wait without hearing from each other before disconnecting, zero uses
+    /// `NETCODE_TIMEOUT_SECONDS`.
+    ///
+    /// See `generate(..)` for the remaining arguments.
+    pub fn generate_with_timeout<H>(hosts: H,
+                       private_key: &[u8; NETCODE_KEY_BYTES],
+                       expire_sec: usize,
+                       timeout_sec: u32,
+                       sequence: u64,
+                       protocol: u64,
+                       client_id: u64,
+                       user_data: Option<&[u8; 256]>)
+                       -> Result<ConnectToken, GenerateError>
+                          where H: ExactSizeIterator<Item=SocketAddr> {
         if hosts.len() > NETCODE_MAX_SERVERS_PER_CONNECT {
             return Err(GenerateError::MaxHostCount)
         }
 
+        let timeout_sec = match timeout_sec {
+            0 => NETCODE_TIMEOUT_SECONDS,
+            t => t
+        };
+
         let now = get_time_now();
         let expire = now + expire_sec as u64;
 
-        let decoded_data = PrivateData::new(client_id, hosts, user_data);
+        let decoded_data = PrivateData::new(client_id, hosts, user_data, timeout_sec);
 
         let mut private_data = [0; NETCODE_CONNECT_TOKEN_PRIVATE_BYTES];
         decoded_data.encode(&mut private_data, protocol, expire, sequence, private_key)?;
@@ -188,7 +221,7 @@ impl ConnectToken {
             private_data: private_data,
             client_to_server_key: decoded_data.client_to_server_key,
             server_to_client_key: decoded_data.server_to_client_key,
-            timeout_sec: NETCODE_TIMEOUT_SECONDS
+            timeout_sec: timeout_sec
         })
     }
 
@@ -258,7 +291,7 @@ impl ConnectToken {
 }
 
 impl PrivateData {
-    pub fn new<H>(client_id: u64, hosts: H, user_data: Option<&[u8; NETCODE_USER_DATA_BYTES]>) -> PrivateData where H: Iterator<Item=SocketAddr> {
+    pub fn new<H>(client_id: u64, hosts: H, user_data: Option<&[u8; NETCODE_USER_DATA_BYTES]>, timeout_sec: u32) -> PrivateData where H: Iterator<Item=SocketAddr> {
         let final_user_data = match user_data {
             Some(u) => {
                 let mut copy_ud: [u8; NETCODE_USER_DATA_BYTES] = [0; NETCODE_USER_DATA_BYTES];
@@ -276,7 +309,8 @@ impl PrivateData {
             hosts: HostList::new(hosts),
             user_data: final_user_data,
             client_to_server_key: client_to_server_key,
-            server_to_client_key: server_to_client_key
+            server_to_client_key: server_to_client_key,
+            timeout_sec: timeout_sec
         }
     }
 
@@ -319,6 +353,7 @@ impl PrivateData {
         out.write(&self.server_to_client_key)?;
 
         out.write(&self.user_data)?;
+        out.write_u32::<LittleEndian>(self.timeout_sec)?;
 
         Ok(())
     }
@@ -336,12 +371,18 @@ impl PrivateData {
         let mut user_data = [0; NETCODE_USER_DATA_BYTES];
         source.read_exact(&mut user_data)?;
 
+        let timeout_sec = match source.read_u32::<LittleEndian>()? {
+            0 => NETCODE_TIMEOUT_SECONDS,
+            t => t
+        };
+
         Ok(PrivateData {
             hosts: hosts,
             client_id: client_id,
             client_to_server_key: client_to_server_key,
             server_to_client_key: server_to_client_key,
-            user_data: user_data
+            user_data: user_data,
+            timeout_sec: timeout_sec
         })
     }
 }
@@ -536,13 +577,42 @@ fn decode() {
     assert_eq!(decoded.client_id, client_id);
     assert_eq!(decoded.client_to_server_key, token.client_to_server_key);
     assert_eq!(decoded.server_to_client_key, token.server_to_client_key);
+    assert_eq!(decoded.timeout_sec, NETCODE_TIMEOUT_SECONDS);
 
     for i in 0..user_data.len() {
         assert_eq!(decoded.user_data[i], user_data[i]);
     }
 }
 
-#[cfg(test)]
+#[test]
+fn decode_timeout() {
+    let mut private_key = [0; NETCODE_KEY_BYTES];
+    crypto::random_bytes(&mut private_key);
+
+    let timeout = 30;
+
+    let mut token = ConnectToken::generate_with_timeout(
+                        [SocketAddr::from_str("127.0.0.1:8080").unwrap()].iter().cloned(),
+                        &private_key,
+                        30, //Expire
+                        timeout,
+                        1,
+                        0x112233445566,
+                        0x665544332211,
+                        None).unwrap();
+
+    assert_eq!(token.timeout_sec, timeout);
+
+    let mut scratch = [0; NETCODE_CONNECT_TOKEN_BYTES];
+    token.write(&mut io::Cursor::new(&mut scratch[..])).unwrap();
+    let read = ConnectToken::read(&mut io::Cursor::new(&scratch[..])).unwrap();
+    assert_eq!(read.timeout_sec, timeout);
+
+    let decoded = token.decode(&private_key).unwrap();
+    assert_eq!(decoded.timeout_sec, timeout);
+}
+
+#[cfg(all(test, feature = "capi-tests"))]
 fn capi_connect_token<I>(hosts: I, private_key: &[u8; NETCODE_KEY_BYTES], expire: i32, client_id: u64, protocol: u64, sequence: u64)
         -> Result<[u8; NETCODE_CONNECT_TOKEN_BYTES], ()>
         where I: Iterator<Item=String> {
@@ -588,6 +658,7 @@ fn capi_connect_token<I>(hosts: I, private_key: &[u8; NETCODE_KEY_BYTES], expire
     result
 }
 
+#[cfg(feature = "capi-tests")]
 #[test]
 fn interop_read() {
     let mut private_key = [0; NETCODE_KEY_BYTES];
@@ -616,6 +687,32 @@ fn interop_read() {
     assert_eq!(conv.expire_utc, conv.create_utc + expire as u64);
 }
 
+#[cfg(feature = "capi-tests")]
+#[test]
+fn interop_default_timeout() {
+    let mut private_key = [0; NETCODE_KEY_BYTES];
+    crypto::random_bytes(&mut private_key);
+
+    let client_id = 0x665544332211;
+
+    let result = capi_connect_token(
+            ["127.0.0.1:8080".to_string()].iter().cloned(),
+            &private_key,
+            30,
+            client_id,
+            0x112233445566,
+            1).unwrap();
+
+    let mut conv = ConnectToken::read(&mut io::Cursor::new(&result[..])).unwrap();
+    assert_eq!(conv.timeout_sec, NETCODE_TIMEOUT_SECONDS);
+
+    //C tokens leave the private timeout zeroed
+    let decoded = conv.decode(&private_key).unwrap();
+    assert_eq!(decoded.client_id, client_id);
+    assert_eq!(decoded.timeout_sec, NETCODE_TIMEOUT_SECONDS);
+}
+
+#[cfg(feature = "capi-tests")]
 #[test]
 fn interop_write() {
     #[allow(unused_variables)]